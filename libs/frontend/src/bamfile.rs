@@ -1,8 +1,8 @@
 use super::hts::*; 
-use std::ffi::{CString, c_void, CStr};
+use super::header::BamHeader;
+use std::ffi::{CString, c_void};
 use std::ptr::{null_mut, null};
 use std::ops::Index;
-use std::slice::from_raw_parts;
 
 use log::error;
 
@@ -244,9 +244,10 @@ pub struct BamFile {
     chrom         : String,
     chrom_id      : u32,
     fp            : *mut htsFile,
-    hdr           : *mut bam_hdr_t,
+    header        : BamHeader,
     idx           : *mut hts_idx_t,
-    length        : usize
+    length        : usize,
+    range         : (usize, usize)
 }
 
 
@@ -294,31 +295,30 @@ impl Drop for  BamFile {
 
 impl BamFile {
     pub fn list_chromosomes(path:&str) -> Result<Vec<String>, ()> {
+        return Ok(BamHeader::read(path, None)?.contigs().into_iter().map(|(name, _)| name).collect());
+    }
+
+    /* Check if the alignment file can be randomly accessed */
+    pub fn has_index(path:&str) -> bool {
+        if path == "-" { return false; }
+
         let fp = unsafe {
             hts_open(CString::new(path).unwrap().as_ptr(), 
                      CString::new("rb").unwrap().as_ptr()) 
         };
 
-        if fp == null_mut() 
-        {
-            error!("Cannot open the alignment file");
-            return Err(());
-        }
-
-        let hdr = unsafe { sam_hdr_read(fp) };
-
-        let raw_names = unsafe{ from_raw_parts((*hdr).target_name, (*hdr).n_targets as usize) };
-
-        let mut ret = Vec::new();
+        if fp == null_mut() { return false; }
 
-        for raw_name in raw_names {
-            let raw_name = unsafe{ CStr::from_ptr(*raw_name as *const i8) };
+        let idx = unsafe { sam_index_load(fp, CString::new(path).unwrap().as_ptr()) };
 
-            ret.push(raw_name.to_string_lossy().to_string());
+        unsafe {
+            if idx != null_mut() { hts_idx_destroy(idx); }
+            hts_close(fp);
         }
 
-        return Ok(ret);
+        return idx != null_mut();
     }
+
     pub fn new<'c,'b>(path:&'c str, chrom:u32, reference:Option<&'b str>) -> Result<Self, ()>
     {
         let fp = unsafe {
@@ -332,11 +332,12 @@ impl BamFile {
             return Err(());
         }
 
-        if reference.is_some() 
+        if let Some(reference) = reference
         {
-            if unsafe{ hts_set_fai_filename(fp, CString::new(reference.unwrap()).unwrap().as_ptr()) } < 0
+            if unsafe{ hts_set_fai_filename(fp, CString::new(reference).unwrap().as_ptr()) } < 0
             {
                 error!("Cannot find the reference file.");
+                unsafe { hts_close(fp) };
                 return Err(());
             }
         }
@@ -346,31 +347,52 @@ impl BamFile {
         if hdr == null_mut()
         {
             error!("Cannot read the header from the alignment file");
+            unsafe { hts_close(fp) };
             return Err(());
         }
 
+        let header = BamHeader::from_raw(hdr);
+
+        let (chrom_name, len) = match (header.contig_name(chrom as usize), header.contig_length(chrom as usize)) {
+            (Some(name), Some(len)) => (name, len),
+            _ => {
+                error!("Invalid chromosome id {}", chrom);
+                unsafe { hts_close(fp) };
+                return Err(());
+            }
+        };
+
         let idx = unsafe { sam_index_load(fp, CString::new(path).unwrap().as_ptr()) };
 
         if idx == null_mut()
         {
             error!("Cannot load the index file, please generate the index file");
+            unsafe { hts_close(fp) };
             return Err(());
         }
 
-        let len = unsafe{ (*(*hdr).target_len.offset(chrom as isize)) };
-
         return Ok(BamFile{
-            chrom : unsafe{ CString::from_raw(*(*hdr).target_name.offset(chrom as isize)).into_string().unwrap() },
+            chrom : chrom_name,
             chrom_id : chrom as u32,
             fp    : fp,
-            hdr   : hdr,
+            header: header,
             idx   : idx,
-            length: len as usize
+            length: len,
+            range : (0, len)
         });
     }
 
     pub fn size(&self) -> usize { self.length }
 
+    /* Restrict the region this file provides as an input of the scanner */
+    pub fn set_range(&mut self, begin:usize, end:usize)
+    {
+        let end = end.min(self.length);
+        self.range = (begin.min(end), end);
+    }
+
+    pub fn get_range(&self) -> (usize, usize) { self.range }
+
     pub fn try_iter(&self) -> Result<BamFileIter, ()> { self.try_iter_range(0, self.length) }
 
     pub fn try_iter_range(&self, begin:usize, end:usize) -> Result<BamFileIter, () >
@@ -401,6 +423,22 @@ impl BamFile {
         });
     }
 
+    pub fn header(&self) -> &BamHeader { &self.header }
+
+    /* Use extra threads for the BGZF decompression, this only pays off for a sequential scan */
+    pub fn set_io_threads(&mut self, nthreads:u32) -> Result<(), ()>
+    {
+        if nthreads == 0 { return Ok(()); }
+
+        if unsafe { hts_set_threads(self.fp, nthreads as i32) } < 0
+        {
+            error!("Cannot start {} decompression threads", nthreads);
+            return Err(());
+        }
+
+        return Ok(());
+    }
+
     pub fn chrom(&self) -> &str
     {
         return &self.chrom[0..];
@@ -423,6 +461,195 @@ impl <'a> Iterator for BamFileIter<'a> {
 
 }
 
+/* Reads the whole alignment file sequentially, without the index. The file should be sorted
+ * by coordinate, the reads are handed out contig by contig in the header order */
+pub struct BamStream {
+    fp     : *mut htsFile,
+    header : BamHeader,
+    buffer : *mut bam1_t,
+    /* The buffer holds a read that isn't handed out yet */
+    pending: bool,
+    eof    : bool,
+    /* The contig id of the last read, used to detect unsorted input */
+    last_tid: i32,
+}
+
+/* The reads of a single contig from the stream, this is an input of the scanner. The reads are
+ * lent out one by one from the buffer of the stream, so no read is copied */
+pub struct ContigReads<'a> {
+    stream : &'a mut BamStream,
+    /* The read in the buffer of the stream has been lent out */
+    lent   : bool,
+    chrom_id: u32,
+    chrom  : String,
+    length : usize,
+}
+
+impl Drop for BamStream {
+    fn drop(&mut self)
+    {
+        if self.buffer != null_mut()
+        {
+            unsafe { bam_destroy1(self.buffer) };
+            self.buffer = null_mut();
+        }
+
+        if self.fp != null_mut()
+        {
+            unsafe { hts_close(self.fp) };
+            self.fp = null_mut();
+        }
+    }
+}
+
+impl BamStream {
+    /* The path "-" reads from the standard input */
+    pub fn open(path:&str, reference:Option<&str>) -> Result<Self, ()>
+    {
+        let fp = unsafe {
+            hts_open(CString::new(path).unwrap().as_ptr(), 
+                     CString::new("rb").unwrap().as_ptr()) 
+        };
+
+        if fp == null_mut() 
+        {
+            error!("Cannot open the alignment file");
+            return Err(());
+        }
+
+        if let Some(reference) = reference
+        {
+            if unsafe{ hts_set_fai_filename(fp, CString::new(reference).unwrap().as_ptr()) } < 0
+            {
+                error!("Cannot find the reference file.");
+                unsafe { hts_close(fp) };
+                return Err(());
+            }
+        }
+
+        let hdr = unsafe { sam_hdr_read(fp) };
+
+        if hdr == null_mut()
+        {
+            error!("Cannot read the header from the alignment file");
+            unsafe { hts_close(fp) };
+            return Err(());
+        }
+
+        let header = BamHeader::from_raw(hdr);
+
+        let buffer = unsafe { bam_init1() };
+
+        if buffer == null_mut()
+        {
+            unsafe { hts_close(fp) };
+            return Err(());
+        }
+
+        return Ok(BamStream { fp, header, buffer, pending: false, eof: false, last_tid: -1 });
+    }
+
+    pub fn header(&self) -> &BamHeader { &self.header }
+
+    pub fn set_io_threads(&mut self, nthreads:u32) -> Result<(), ()>
+    {
+        if nthreads == 0 { return Ok(()); }
+
+        if unsafe { hts_set_threads(self.fp, nthreads as i32) } < 0
+        {
+            error!("Cannot start {} decompression threads", nthreads);
+            return Err(());
+        }
+
+        return Ok(());
+    }
+
+    /* Make sure the buffer holds the next read, returns its contig id, or None at the end of
+     * the mapped reads. A read error or the unsorted input is an error, since the rest of the
+     * file can't be scanned */
+    fn peek(&mut self) -> Result<Option<i32>, ()>
+    {
+        if !self.pending && !self.eof
+        {
+            let rc = unsafe { sam_read1(self.fp, self.header.as_ptr(), self.buffer) };
+
+            if rc < -1
+            {
+                error!("Failed to read the alignment file");
+                return Err(());
+            }
+
+            if rc < 0 { self.eof = true; }
+            else { self.pending = true; }
+        }
+
+        if self.eof { return Ok(None); }
+
+        let tid = unsafe { (*self.buffer).core.tid };
+
+        /* The unmapped reads are placed at the end of a sorted file */
+        if tid < 0 { self.eof = true; return Ok(None); }
+
+        if tid < self.last_tid
+        {
+            error!("The alignment file isn't sorted by coordinate, sort it or index it before calling");
+            return Err(());
+        }
+
+        self.last_tid = tid;
+
+        return Ok(Some(tid));
+    }
+
+    /* The reads of the contig, the reads of the contigs before it are skipped. The contigs
+     * should be requested in the header order */
+    pub fn contig<'a>(&'a mut self, chrom:u32) -> Result<ContigReads<'a>, ()>
+    {
+        let (chrom_name, length) = match (self.header.contig_name(chrom as usize), self.header.contig_length(chrom as usize)) {
+            (Some(name), Some(len)) => (name, len),
+            _ => {
+                error!("Invalid chromosome id {}", chrom);
+                return Err(());
+            }
+        };
+
+        while let Some(tid) = self.peek()?
+        {
+            if tid >= chrom as i32 { break; }
+            self.pending = false;
+        }
+
+        return Ok(ContigReads {
+            stream: self,
+            lent: false,
+            chrom_id: chrom,
+            chrom: chrom_name,
+            length
+        });
+    }
+}
+
+impl <'a> ContigReads<'a> {
+    pub fn size(&self) -> usize { self.length }
+    pub fn chrom(&self) -> &str { self.chrom.as_str() }
+
+    /* The next read of the contig, which is valid until the next call */
+    pub fn next_read(&mut self) -> Result<Option<Alignment<'_>>, ()>
+    {
+        if self.lent
+        {
+            self.stream.pending = false;
+            self.lent = false;
+        }
+
+        if self.stream.peek()? != Some(self.chrom_id as i32) { return Ok(None); }
+
+        self.lent = true;
+
+        return Ok(Some(Alignment { data : unsafe { self.stream.buffer.as_ref().unwrap() } }));
+    }
+}
+
 #[cfg(test)]
 mod bamfile_test {
     use super::*;
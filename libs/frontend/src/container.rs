@@ -0,0 +1,470 @@
+/* The whole-genome scanner dump container.
+ *
+ * A container holds the scanner dumps of any number of chromosomes in a single file, all the
+ * integers are little-endian.
+ *
+ *   magic       8 bytes   "LIMOPACK"
+ *   version     u32       CONTAINER_VERSION
+ *   fingerprint u32 length followed by the fingerprint text (since version 2)
+ *   sections    until the end of file, each of them is
+ *                magic "LIMOSECT", name_len u32, contig name (UTF-8), contig length u64,
+ *                payload_len u64, payload
+ *              and the payload is a scanner dump as described in the dump module.
+ *
+ * The table of contents is built by walking through the section headers, so a chromosome
+ * can be loaded without reading any other section. Sections are only appended, and a section
+ * is appended with a single write under a lock, so the threads of a run can share the same
+ * container. If a run was interrupted in the middle of an append, the incomplete section is
+ * discarded the next time the container is opened. When a contig shows up more than once,
+ * the last section wins.
+ *
+ * The fingerprint is a list of key=value lines describing the alignment file the container
+ * was built from and the scan parameters. When the container doesn't match the fingerprint of
+ * the current run, all the sections are stale and the container is rebuilt, unless the caller
+ * explicitly trusts it.
+ */
+use std::fs::{File, OpenOptions};
+use std::time::UNIX_EPOCH;
+use std::io::{Read, Write, Seek, SeekFrom, BufReader, Error};
+use std::sync::Mutex;
+
+use crate::dump::{DumpEncoding, DUMP_VERSION, MAX_NAME_LEN, crc32, invalid_data};
+use crate::scanner::{Scanner, MapqConfig};
+use crate::header::BamHeader;
+use crate::read_filter::ReadFilter;
+
+use log::{warn, error};
+
+pub const CONTAINER_MAGIC: &[u8; 8] = b"LIMOPACK";
+pub const CONTAINER_VERSION: u32 = 2;
+const SECTION_MAGIC: &[u8; 8] = b"LIMOSECT";
+
+#[derive(Debug, Clone)]
+pub struct TocEntry {
+    pub name   : String,
+    pub length : u64,
+    /* The file offset of the payload */
+    pub offset : u64,
+    pub size   : u64
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Fingerprint {
+    fields: Vec<(String, String)>
+}
+
+impl Fingerprint {
+    pub fn add<T: ToString>(mut self, key: &str, value: T) -> Self
+    {
+        self.fields.push((key.to_string(), value.to_string()));
+        return self;
+    }
+
+    /* The fingerprint of the alignment file and the scan parameters */
+    pub fn of_alignment(path: &str, filter: &ReadFilter, mapq: &MapqConfig) -> Result<Self, ()>
+    {
+        let metadata = std::fs::metadata(path).map_err(|e| { error!("Cannot stat the alignment file {}: {:?}", path, e); })?;
+        let mtime = metadata.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let full_path = std::fs::canonicalize(path).map_or(path.to_string(), |p| p.to_string_lossy().to_string());
+        let header = BamHeader::read(path, None)?.text();
+
+        let mut ret = Fingerprint::default()
+            .add("path", full_path)
+            .add("size", metadata.len())
+            .add("mtime", mtime)
+            .add("header_crc32", format!("{:08x}", crc32(header.as_bytes())))
+            .add("dump_version", DUMP_VERSION);
+
+        for (key, value) in Scanner::scan_params(filter, mapq)
+        {
+            ret = ret.add(key, value);
+        }
+
+        return Ok(ret);
+    }
+
+    fn serialize(&self) -> Vec<u8>
+    {
+        return self.fields.iter().map(|(k, v)| format!("{}={}\n", k, v)).collect::<String>().into_bytes();
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, Error>
+    {
+        let text = std::str::from_utf8(data).map_err(|_| invalid_data("invalid fingerprint".to_string()))?;
+        let mut fields = Vec::new();
+
+        for line in text.lines()
+        {
+            let mut kv = line.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => fields.push((k.to_string(), v.to_string())),
+                _ => return Err(invalid_data(format!("invalid fingerprint line: {}", line)))
+            }
+        }
+
+        return Ok(Fingerprint { fields });
+    }
+
+    /* The human readable description of the fields that differ */
+    fn diff(&self, other: &Fingerprint) -> Vec<String>
+    {
+        let lookup = |fp: &Fingerprint, key: &str| fp.fields.iter().find(|(k, _)| k == key).map_or("<none>".to_string(), |(_, v)| v.clone());
+
+        let keys:Vec<_> = self.fields.iter().chain(other.fields.iter()).map(|(k, _)| k.as_str()).collect();
+
+        let mut ret = Vec::new();
+
+        for (idx, key) in keys.iter().enumerate()
+        {
+            if keys[..idx].contains(key) { continue; }
+
+            let (this, that) = (lookup(self, key), lookup(other, key));
+
+            if this != that
+            {
+                ret.push(format!("{}: {} != {}", key, this, that));
+            }
+        }
+
+        return ret;
+    }
+}
+
+pub struct DumpContainer {
+    path : String,
+    state: Mutex<(File, Vec<TocEntry>)>
+}
+
+fn read_u32<R:Read>(fp: &mut R) -> Result<u32, Error>
+{
+    let mut buf = [0u8; 4];
+    fp.read_exact(&mut buf)?;
+    return Ok(u32::from_le_bytes(buf));
+}
+
+fn read_u64<R:Read>(fp: &mut R) -> Result<u64, Error>
+{
+    let mut buf = [0u8; 8];
+    fp.read_exact(&mut buf)?;
+    return Ok(u64::from_le_bytes(buf));
+}
+
+/* Read the section header at the current position, returns the TOC entry and the offset of
+ * the next section */
+fn read_section<R:Read>(fp: &mut R, begin: u64) -> Result<(TocEntry, u64), Error>
+{
+    let mut magic = [0u8; 8];
+    fp.read_exact(&mut magic)?;
+
+    if &magic != SECTION_MAGIC
+    {
+        return Err(invalid_data(format!("invalid section header at offset {}", begin)));
+    }
+
+    let name_len = read_u32(fp)? as usize;
+
+    if name_len > MAX_NAME_LEN
+    {
+        return Err(invalid_data(format!("invalid contig name length {} at offset {}", name_len, begin)));
+    }
+
+    let mut name = vec![0u8; name_len];
+    fp.read_exact(&mut name[0..])?;
+    let name = String::from_utf8(name).map_err(|_| invalid_data(format!("invalid contig name at offset {}", begin)))?;
+    let length = read_u64(fp)?;
+    let size = read_u64(fp)?;
+    let overflow = || invalid_data(format!("invalid section size at offset {}", begin));
+    let offset = begin.checked_add((SECTION_MAGIC.len() + 4 + name_len + 16) as u64).ok_or_else(overflow)?;
+    let next = offset.checked_add(size).ok_or_else(overflow)?;
+
+    return Ok((TocEntry { name, length, offset, size }, next));
+}
+
+impl DumpContainer {
+    fn write_header(fp: &mut File, fingerprint: &Fingerprint) -> Result<(), Error>
+    {
+        let fingerprint = fingerprint.serialize();
+
+        let mut header = Vec::new();
+        header.extend_from_slice(CONTAINER_MAGIC);
+        header.extend_from_slice(&CONTAINER_VERSION.to_le_bytes());
+        header.extend_from_slice(&(fingerprint.len() as u32).to_le_bytes());
+        header.extend_from_slice(&fingerprint[0..]);
+
+        fp.set_len(0)?;
+        fp.seek(SeekFrom::Start(0))?;
+        fp.write_all(&header[0..])?;
+        fp.flush()?;
+
+        return Ok(());
+    }
+
+    /* The per-chromosome dumps named <path>-<chrom id> written by the older versions, which are
+     * replaced by the container */
+    fn legacy_dumps(path: &str) -> Vec<String>
+    {
+        let path = std::path::Path::new(path);
+        let prefix = format!("{}-", path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string()));
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| std::path::Path::new("."));
+
+        let mut ret:Vec<_> = std::fs::read_dir(dir).into_iter().flatten().flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.strip_prefix(&prefix[0..]).is_some_and(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit())))
+            .map(|name| dir.join(name).to_string_lossy().to_string())
+            .collect();
+
+        ret.sort();
+
+        return ret;
+    }
+
+    /* Open the container, or create an empty one if the file doesn't exist. If the container
+     * was built from a different alignment file or with different scan parameters, it's
+     * rebuilt from scratch, unless trust is set */
+    pub fn open(path: &str, fingerprint: &Fingerprint, trust: bool) -> Result<Self, Error>
+    {
+        let legacy = Self::legacy_dumps(path);
+
+        if !legacy.is_empty()
+        {
+            warn!("The per-chromosome scanner dumps {} are from an older version and aren't used any more, they can be deleted", legacy.join(", "));
+        }
+
+        let mut fp = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+
+        let file_size = fp.metadata()?.len();
+
+        if file_size == 0
+        {
+            Self::write_header(&mut fp, fingerprint)?;
+            return Ok(DumpContainer { path: path.to_string(), state: Mutex::new((fp, Vec::new())) });
+        }
+
+        let mut reader = BufReader::new(&mut fp);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).map_err(|_| invalid_data(format!("{} is not a Limo scanner dump container", path)))?;
+
+        if &magic != CONTAINER_MAGIC
+        {
+            return Err(invalid_data(format!("{} is not a Limo scanner dump container", path)));
+        }
+
+        let version = read_u32(&mut reader)?;
+
+        if version == 0 || version > CONTAINER_VERSION
+        {
+            return Err(invalid_data(format!("unsupported scanner dump container version {} (expected 1 to {})", version, CONTAINER_VERSION)));
+        }
+
+        /* The version 1 container doesn't carry a fingerprint */
+        let (stored, mut pos) = if version >= 2 {
+            let size = read_u32(&mut reader)? as usize;
+
+            if size as u64 > file_size
+            {
+                return Err(invalid_data(format!("malformed fingerprint of {} bytes in {}", size, path)));
+            }
+
+            let mut data = vec![0u8; size];
+            reader.read_exact(&mut data[0..])?;
+            (Fingerprint::parse(&data[0..])?, (CONTAINER_MAGIC.len() + 8 + size) as u64)
+        } else {
+            (Fingerprint::default(), (CONTAINER_MAGIC.len() + 4) as u64)
+        };
+
+        let mut toc = Vec::new();
+
+        while pos < file_size
+        {
+            reader.seek(SeekFrom::Start(pos))?;
+
+            match read_section(&mut reader, pos) {
+                Ok((entry, next)) if next <= file_size => {
+                    toc.push(entry);
+                    pos = next;
+                },
+                _ => {
+                    warn!("Discarding the incomplete section at offset {} of the scanner dump container {}", pos, path);
+                    break;
+                }
+            }
+        }
+
+        drop(reader);
+
+        if &stored != fingerprint
+        {
+            for field in stored.diff(fingerprint)
+            {
+                warn!("The scanner dump {} was built with a different {}", path, field);
+            }
+
+            if !trust
+            {
+                warn!("Discarding the {} scanned contigs of the stale scanner dump {} and rebuilding it from the alignment file. Use --trust-dump to use it anyway", toc.len(), path);
+                Self::write_header(&mut fp, fingerprint)?;
+                return Ok(DumpContainer { path: path.to_string(), state: Mutex::new((fp, Vec::new())) });
+            }
+
+            warn!("The scanner dump {} doesn't match the alignment file, but it's trusted as requested", path);
+        }
+
+        if pos < file_size
+        {
+            fp.set_len(pos)?;
+        }
+
+        return Ok(DumpContainer { path: path.to_string(), state: Mutex::new((fp, toc)) });
+    }
+
+    pub fn get_path(&self) -> &str { self.path.as_str() }
+
+    pub fn toc(&self) -> Vec<TocEntry>
+    {
+        return self.state.lock().unwrap().1.clone();
+    }
+
+    fn find(&self, name: &str) -> Option<TocEntry>
+    {
+        return self.state.lock().unwrap().1.iter().rev().find(|e| e.name == name).cloned();
+    }
+
+    pub fn contains(&self, name: &str, length: u64) -> bool
+    {
+        return self.find(name).map_or(false, |entry| entry.length == length);
+    }
+
+    /* Load the scanner of the contig, None if the container doesn't have the contig or the
+     * contig length doesn't match */
+    pub fn load(&self, name: &str, length: u64) -> Result<Option<Scanner>, Error>
+    {
+        let entry = if let Some(entry) = self.find(name) { entry } else { return Ok(None); };
+
+        if entry.length != length
+        {
+            warn!("The length of {} in the scanner dump container is {}, but {} is expected", name, entry.length, length);
+            return Ok(None);
+        }
+
+        /* Sections are never modified once they are written, so we don't need the lock */
+        let mut fp = File::open(self.path.as_str())?;
+        fp.seek(SeekFrom::Start(entry.offset))?;
+
+        let scanner = Scanner::try_load(&mut BufReader::new(fp.take(entry.size)))?;
+
+        if scanner.get_chrom() != name || scanner.chrom_size() as u64 != length
+        {
+            return Err(invalid_data(format!("the section of {} contains the scanner of a different contig", name)));
+        }
+
+        return Ok(Some(scanner));
+    }
+
+    pub fn append(&self, scanner: &Scanner, encoding: DumpEncoding) -> Result<(), Error>
+    {
+        let mut payload = Vec::new();
+        scanner.try_dump(&mut payload, encoding)?;
+
+        let name = scanner.get_chrom().as_bytes();
+        let mut section = Vec::with_capacity(payload.len() + name.len() + 28);
+        section.extend_from_slice(SECTION_MAGIC);
+        section.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        section.extend_from_slice(name);
+        section.extend_from_slice(&(scanner.chrom_size() as u64).to_le_bytes());
+        section.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        let header_size = section.len() as u64;
+        section.extend_from_slice(&payload[0..]);
+
+        let mut state = self.state.lock().unwrap();
+        let begin = state.0.seek(SeekFrom::End(0))?;
+
+        if let Err(e) = state.0.write_all(&section[0..]).and_then(|_| state.0.flush())
+        {
+            /* Do not leave a partial section behind, otherwise all the sections appended after
+             * it are unreachable */
+            state.0.set_len(begin).unwrap_or_else(|_| ());
+            return Err(e);
+        }
+
+        state.1.push(TocEntry {
+            name  : scanner.get_chrom().to_string(),
+            length: scanner.chrom_size() as u64,
+            offset: begin + header_size,
+            size  : payload.len() as u64
+        });
+
+        return Ok(());
+    }
+}
+#[cfg(test)]
+mod container_test {
+    use super::*;
+    use crate::test_util::TempFile;
+
+    #[test]
+    fn test_fingerprint()
+    {
+        let fingerprint = Fingerprint::default().add("path", "/data/a.bam").add("size", 1024);
+        let parsed = Fingerprint::parse(&fingerprint.serialize()[0..]).unwrap();
+        assert_eq!(parsed, fingerprint);
+
+        let other = Fingerprint::default().add("path", "/data/a.bam").add("size", 2048);
+        assert_eq!(fingerprint.diff(&other), vec!["size: 1024 != 2048".to_string()]);
+    }
+
+    #[test]
+    fn test_corrupted_section()
+    {
+        let section = |name_len: u32, size: u64| {
+            let mut buf = SECTION_MAGIC.to_vec();
+            buf.extend_from_slice(&name_len.to_le_bytes());
+            buf.extend_from_slice(b"chr1");
+            buf.extend_from_slice(&1000u64.to_le_bytes());
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf
+        };
+
+        let (entry, next) = read_section(&mut &section(4, 100)[0..], 16).unwrap();
+        assert_eq!((entry.name.as_str(), entry.length, entry.offset, entry.size, next), ("chr1", 1000, 48, 100, 148));
+
+        assert!(read_section(&mut &section(u32::max_value(), 100)[0..], 16).is_err());
+        assert!(read_section(&mut &section(4, u64::max_value() - 10)[0..], 16).is_err());
+    }
+
+    #[test]
+    fn test_stale_container() -> Result<(), Error>
+    {
+        let file = TempFile::new("container");
+        let path = file.path();
+
+        let current = Fingerprint::default().add("size", 1);
+        let stale = Fingerprint::default().add("size", 2);
+
+        let read_all = || -> Result<Vec<u8>, Error> {
+            let mut data = Vec::new();
+            File::open(path)?.read_to_end(&mut data)?;
+            Ok(data)
+        };
+
+        drop(DumpContainer::open(path, &stale, false)?);
+        assert!(read_all()?.ends_with(&stale.serialize()[0..]));
+
+        /* The trusted container is kept as it is */
+        drop(DumpContainer::open(path, &current, true)?);
+        assert!(read_all()?.ends_with(&stale.serialize()[0..]));
+
+        /* Otherwise it's rebuilt with the new fingerprint */
+        drop(DumpContainer::open(path, &current, false)?);
+        assert!(read_all()?.ends_with(&current.serialize()[0..]));
+
+        /* The per-chromosome dumps of the older versions */
+        let legacy:Vec<_> = ["-0", "-12", "-x", "-"].iter().map(|suffix| file.sibling(suffix)).collect();
+        for legacy_file in legacy.iter() { File::create(legacy_file.path())?; }
+        assert_eq!(DumpContainer::legacy_dumps(path), vec![legacy[0].path().to_string(), legacy[1].path().to_string()]);
+
+        return Ok(());
+    }
+}
@@ -0,0 +1,220 @@
+/* The reader of the precomputed per-base coverage, such as the mosdepth per-base BED or a
+ * bedGraph. Each line is "chrom begin end depth", 0-based and half-open, and the file can be
+ * plain text, gzip or bgzip compressed. The lines of a contig should be contiguous and the
+ * contigs should be in the same order as the contig list, which is the case for the files
+ * produced from a coordinate sorted alignment. */
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::{BufRead, BufReader};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr::null_mut;
+use std::str::FromStr;
+
+use log::{error, warn};
+
+#[link(name = "z")]
+extern "C" {
+    fn gzopen(path: *const c_char, mode: *const c_char) -> *mut c_void;
+    fn gzgets(file: *mut c_void, buf: *mut c_char, len: c_int) -> *mut c_char;
+    fn gzclose(file: *mut c_void) -> c_int;
+}
+
+/* The lines of a plain text, gzip or bgzip compressed file, read through zlib */
+pub struct GzLines {
+    fp     : *mut c_void,
+    buffer : Vec<u8>,
+}
+
+impl Drop for GzLines {
+    fn drop(&mut self)
+    {
+        if self.fp != null_mut()
+        {
+            unsafe { gzclose(self.fp) };
+            self.fp = null_mut();
+        }
+    }
+}
+
+impl GzLines {
+    pub fn open(path: &str) -> Result<Self, ()>
+    {
+        let fp = unsafe { gzopen(CString::new(path).unwrap().as_ptr(), CString::new("rb").unwrap().as_ptr()) };
+
+        if fp == null_mut()
+        {
+            error!("Cannot open {}", path);
+            return Err(());
+        }
+
+        return Ok(GzLines { fp, buffer: vec![0u8; 4096] });
+    }
+}
+
+impl Iterator for GzLines {
+    type Item = String;
+
+    /* The line without the line break */
+    fn next(&mut self) -> Option<String>
+    {
+        let mut line = Vec::new();
+
+        loop {
+            let ret = unsafe { gzgets(self.fp, self.buffer.as_mut_ptr() as *mut c_char, self.buffer.len() as c_int) };
+
+            if ret == null_mut() { break; }
+
+            let len = self.buffer.iter().position(|c| *c == 0).unwrap_or(self.buffer.len());
+            line.extend_from_slice(&self.buffer[..len]);
+
+            if line.last() == Some(&b'\n') { break; }
+        }
+
+        if line.is_empty() { return None; }
+
+        return Some(String::from_utf8_lossy(&line[0..]).trim_end().to_string());
+    }
+}
+
+pub struct CoverageReader {
+    path   : String,
+    lines  : GzLines,
+    /* The rank of each contig in the contig list */
+    ranks  : HashMap<String, usize>,
+    /* The line that is read but not consumed yet, (rank, begin, end, depth) */
+    pending: Option<(usize, usize, usize, i32)>,
+    eof    : bool,
+}
+
+impl CoverageReader {
+    pub fn open(path: &str, contigs: &[(String, usize)]) -> Result<Self, ()>
+    {
+        let lines = GzLines::open(path).map_err(|_| { error!("Cannot open the coverage file {}", path); })?;
+
+        return Ok(CoverageReader {
+            path   : path.to_string(),
+            lines,
+            ranks  : contigs.iter().enumerate().map(|(idx, (name, _))| (name.clone(), idx)).collect(),
+            pending: None,
+            eof    : false,
+        });
+    }
+
+    /* Make sure the next interval of a known contig is pending, None at the end of file */
+    fn peek(&mut self) -> Result<Option<usize>, ()>
+    {
+        while self.pending.is_none() && !self.eof
+        {
+            let line = if let Some(line) = self.lines.next() { line } else { self.eof = true; break; };
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with("track") { continue; }
+
+            let fields:Vec<_> = line.split('\t').collect();
+
+            if fields.len() < 4
+            {
+                error!("Malformed coverage line in {}: {}", self.path, line);
+                return Err(());
+            }
+
+            let rank = if let Some(rank) = self.ranks.get(fields[0]) { *rank } else { continue; };
+
+            let parsed = (usize::from_str(fields[1]), usize::from_str(fields[2]), f64::from_str(fields[3]));
+
+            /* bedGraph may carry the mean depth of the interval */
+            let (begin, end, depth) = if let (Ok(begin), Ok(end), Ok(depth)) = parsed { (begin, end, depth.round() as i32) } else {
+                error!("Malformed coverage line in {}: {}", self.path, line);
+                return Err(());
+            };
+
+            self.pending = Some((rank, begin, end, depth));
+        }
+
+        return Ok(self.pending.map(|(rank, _, _, _)| rank));
+    }
+
+    /* Call update with (begin, end, depth) for each interval of the contig. The intervals of
+     * the contigs before it are skipped, so the contigs should be requested in order */
+    pub fn read_contig<F:FnMut(usize, usize, i32)>(&mut self, chrom: &str, mut update: F) -> Result<(), ()>
+    {
+        let target = if let Some(rank) = self.ranks.get(chrom) { *rank } else {
+            error!("Unknown contig {}", chrom);
+            return Err(());
+        };
+
+        let mut found = false;
+
+        while let Some(rank) = self.peek()?
+        {
+            if rank > target { break; }
+
+            let (_, begin, end, depth) = self.pending.take().unwrap();
+
+            if rank == target && begin < end && depth != 0
+            {
+                update(begin, end, depth);
+                found = true;
+            }
+        }
+
+        if !found
+        {
+            warn!("The coverage file {} doesn't have any data for {}, it may not be sorted in the contig order", self.path, chrom);
+        }
+
+        return Ok(());
+    }
+}
+
+/* Load the contig list from the FASTA index */
+pub fn load_fai(path: &str) -> Result<Vec<(String, usize)>, ()>
+{
+    let fp = std::fs::File::open(path).map_err(|e| { error!("Cannot open the FASTA index {}: {:?}", path, e); })?;
+
+    let mut ret = Vec::new();
+
+    for line in BufReader::new(fp).lines()
+    {
+        let line = line.map_err(|e| { error!("Cannot read the FASTA index {}: {:?}", path, e); })?;
+        let fields:Vec<_> = line.split('\t').collect();
+
+        if fields.len() < 2 { continue; }
+
+        let length = usize::from_str(fields[1]).map_err(|_| { error!("Malformed FASTA index line in {}: {}", path, line); })?;
+        ret.push((fields[0].to_string(), length));
+    }
+
+    return Ok(ret);
+}
+
+#[cfg(test)]
+mod coverage_test {
+    use super::*;
+    use crate::scanner::Scanner;
+    use crate::test_util::TempFile;
+
+    #[test]
+    fn test_coverage_scanner()
+    {
+        let (total_file, mapq0_file) = (TempFile::new("coverage-total"), TempFile::new("coverage-mapq0"));
+        let (total_path, mapq0_path) = (total_file.path(), mapq0_file.path());
+
+        std::fs::write(total_path, "chr1\t0\t1000\t30\nchr2\t0\t5\t1\nchr3\t0\t5\t3.4\n").unwrap();
+        std::fs::write(mapq0_path, "chr1\t0\t1000\t10.2\n").unwrap();
+
+        let contigs = vec![("chr1".to_string(), 1000), ("chr2".to_string(), 10), ("chr3".to_string(), 10)];
+        let mut total = CoverageReader::open(total_path, &contigs).unwrap();
+        let mut mapq0 = CoverageReader::open(mapq0_path, &contigs).unwrap();
+
+        let scanner = Scanner::from_coverage("chr1", 1000, 100, &mut total, Some(&mut mapq0)).unwrap();
+        let depth = |window: &crate::window::Window<i32>| window.iter(1).nth(500).map(|x:i32| x);
+        assert_eq!(depth(scanner.get_corrected()), Some(30));
+        assert_eq!(depth(scanner.get_raw_window()), Some(20));
+        assert_eq!(depth(scanner.get_low_mq_window()), Some(10));
+
+        /* chr2 is skipped */
+        let mut intervals = Vec::new();
+        total.read_contig("chr3", |begin, end, depth| intervals.push((begin, end, depth))).unwrap();
+        assert_eq!(intervals, vec![(0, 5, 3)]);
+    }
+}
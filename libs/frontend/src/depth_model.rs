@@ -1,14 +1,45 @@
 use std::fmt::Debug;
 use crate::scanner::Scanner;
 
+use log::error;
+
 pub trait DepthModel where Self:Clone {
     type Input : Copy + From<f64>;
     type Output: PartialOrd + Default + Debug + Clone;
-    type ParamType : Copy;
+    type ParamType : Copy + Debug;
     fn determine_default_param(scanner:&Scanner, window_size: u32, copy_nums: &[u32]) -> Self::ParamType;
+    /* Set a field of the model parameter by its name */
+    fn set_param(param: &mut Self::ParamType, key: &str, value: &str) -> Result<(), ()>;
+    /* Override the default parameter with a list like "key1=value1,key2=value2" */
+    fn parse_param(default: Self::ParamType, text: &str) -> Result<Self::ParamType, ()>
+    {
+        let mut ret = default;
+
+        for item in text.split(',').filter(|item| !item.is_empty())
+        {
+            let mut kv = item.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => Self::set_param(&mut ret, key.trim(), value.trim())?,
+                _ => {
+                    error!("Invalid model parameter {}, it should be key=value", item);
+                    return Err(());
+                }
+            }
+        }
+
+        return Ok(ret);
+    }
     fn score_cmp(left : Self::Output, right : Self::Output) -> i32;
-    fn score_threshold(win_size:u32, score : Self::Output) -> bool;
+    /* The threshold used when it's neither given nor calibrated from the data */
+    fn default_score_threshold(win_size:u32) -> Self::Output;
+    fn score_threshold(threshold : Self::Output, score : Self::Output) -> bool;
+    /* The copy numbers the models are created for, all the target ones by default */
+    fn model_copy_nums(copy_nums: &[u32]) -> Vec<u32> { copy_nums.to_vec() }
     fn create_model(copy_num:u32, left_side:bool, param:Self::ParamType) -> Self;
     fn put(&mut self, next : Self::Input) -> ();
     fn get_score(&self) -> Self::Output;
+    /* The copy number estimated from the depth, None if the model assumes its copy number */
+    fn estimated_copy_num(&self) -> Option<u32> { None }
+    /* The depth level after the change relative to the neutral level, if the model estimates it */
+    fn estimated_level(&self) -> Option<f64> { None }
 }
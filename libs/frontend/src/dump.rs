@@ -0,0 +1,453 @@
+/* The scanner dump format.
+ *
+ * All the integers are little-endian, regardless of the machine that produced the dump.
+ *
+ *   magic      8 bytes   "LIMODUMP"
+ *   version    u32       DUMP_VERSION
+ *   header     u64       length of the header section in bytes, followed by the section:
+ *                          name_len u32, chrom name (UTF-8), common_read_len u32,
+ *                          common_read_len_cnt u32, size u32, offset u32
+ *   windows    u32       number of window sections, each of them is
+ *                          num_elements u64, encoding u32, payload_len u64, payload
+ *                        the first three are the corrected, low MAPQ and raw depth, each of
+ *                        the following MAPQ tier windows is preceded by its cutoff as an u32
+ *   checksum   u32       CRC-32 of everything above, including the magic number
+ *
+ * The payload of a window is the acc array followed by the ext array, num_elements each.
+ * With the raw encoding (0), they are stored as i32. With the compressed encoding (1), the
+ * values are zigzag varints, which are split into blocks of BLOCK_SIZE values, and each block
+ * is deflated by zlib. The compressed payload is the length of the compressed acc array in
+ * bytes as an u64, followed by the compressed acc array and the compressed ext array, and
+ * each compressed array is
+ *   num_blocks u32, then for each block: raw_len u32, compressed_len u32, compressed data
+ *
+ * The loader rejects any file with a different magic number, version
+ * or checksum, as well as any section whose length doesn't match its content. The lengths are
+ * checked against the chromosome size before anything is allocated, so a corrupted dump is an
+ * error instead of a huge allocation.
+ */
+use std::io::{Read, Write, Error, ErrorKind};
+use std::os::raw::{c_int, c_ulong};
+
+pub const DUMP_MAGIC: &[u8; 8] = b"LIMODUMP";
+pub const DUMP_VERSION: u32 = 1;
+
+/* The number of values in a compressed block */
+const BLOCK_SIZE: usize = 1 << 20;
+/* The longest zigzag varint of an i32 */
+const MAX_VARINT_LEN: usize = 5;
+/* The longest chromosome name, anything longer is a corrupted header */
+pub const MAX_NAME_LEN: usize = 4096;
+/* The sections are read in chunks of this size, so a corrupted length fails at the end of
+ * the file instead of allocating the whole length up front */
+const READ_CHUNK_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DumpEncoding {
+    Raw = 0,
+    Compressed = 1
+}
+
+impl DumpEncoding {
+    pub fn from_u32(val: u32) -> Option<Self>
+    {
+        match val {
+            0 => Some(DumpEncoding::Raw),
+            1 => Some(DumpEncoding::Compressed),
+            _ => None
+        }
+    }
+}
+
+#[link(name = "z")]
+extern "C" {
+    fn compressBound(source_len: c_ulong) -> c_ulong;
+    fn compress2(dest: *mut u8, dest_len: *mut c_ulong, source: *const u8, source_len: c_ulong, level: c_int) -> c_int;
+    fn uncompress(dest: *mut u8, dest_len: *mut c_ulong, source: *const u8, source_len: c_ulong) -> c_int;
+}
+
+fn deflate_block(data: &[u8]) -> Result<Vec<u8>, Error>
+{
+    let mut size = unsafe { compressBound(data.len() as c_ulong) };
+    let mut ret = vec![0u8; size as usize];
+
+    if unsafe { compress2(ret.as_mut_ptr(), &mut size, data.as_ptr(), data.len() as c_ulong, 6) } != 0
+    {
+        return Err(Error::new(ErrorKind::Other, "zlib compression failed"));
+    }
+
+    ret.truncate(size as usize);
+    return Ok(ret);
+}
+
+fn inflate_block(data: &[u8], raw_len: usize) -> Result<Vec<u8>, Error>
+{
+    let mut size = raw_len as c_ulong;
+    let mut ret = vec![0u8; raw_len];
+
+    if unsafe { uncompress(ret.as_mut_ptr(), &mut size, data.as_ptr(), data.len() as c_ulong) } != 0 || size as usize != raw_len
+    {
+        return Err(invalid_data("cannot decompress the window data block".to_string()));
+    }
+
+    return Ok(ret);
+}
+
+fn encode_varint(val: i32, buf: &mut Vec<u8>)
+{
+    let mut zz = ((val << 1) ^ (val >> 31)) as u32;
+    while zz >= 0x80
+    {
+        buf.push((zz as u8) | 0x80);
+        zz >>= 7;
+    }
+    buf.push(zz as u8);
+}
+
+fn decode_varints(buf: &[u8], out: &mut Vec<i32>) -> Result<(), Error>
+{
+    let mut zz = 0u32;
+    let mut shift = 0;
+
+    for byte in buf
+    {
+        if shift > 28
+        {
+            return Err(invalid_data("malformed varint in the window data".to_string()));
+        }
+
+        zz |= ((byte & 0x7f) as u32) << shift;
+
+        if byte & 0x80 == 0
+        {
+            out.push(((zz >> 1) as i32) ^ -((zz & 1) as i32));
+            zz = 0;
+            shift = 0;
+        }
+        else { shift += 7; }
+    }
+
+    if shift != 0
+    {
+        return Err(invalid_data("truncated varint in the window data".to_string()));
+    }
+
+    return Ok(());
+}
+
+/* Encode the values as the payload of the compressed encoding */
+pub fn compress_i32(data: &[i32]) -> Result<Vec<u8>, Error>
+{
+    let mut ret = Vec::new();
+    let mut raw = Vec::with_capacity(BLOCK_SIZE);
+
+    let num_blocks = (data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    ret.extend_from_slice(&(num_blocks as u32).to_le_bytes());
+
+    for block in data.chunks(BLOCK_SIZE)
+    {
+        raw.clear();
+        block.iter().for_each(|v| encode_varint(*v, &mut raw));
+
+        let compressed = deflate_block(&raw[0..])?;
+        ret.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        ret.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        ret.extend_from_slice(&compressed[0..]);
+    }
+
+    return Ok(ret);
+}
+
+/* The upper bound of the compressed window payload of size elements, which is the length of
+ * the acc array and the two compressed arrays. zlib adds at most 13 bytes to a block plus
+ * 0.1% of the input, the block headers are covered by the slack */
+pub fn max_compressed_size(size: usize) -> usize
+{
+    let num_blocks = size / BLOCK_SIZE + 1;
+    let raw_len = size.saturating_mul(MAX_VARINT_LEN);
+    let array = raw_len.saturating_add(raw_len / 1000).saturating_add(num_blocks.saturating_mul(64));
+    return array.saturating_mul(2).saturating_add(16);
+}
+
+/* Decode the payload of the compressed encoding, which should contain exactly size values */
+pub fn decompress_i32(payload: &[u8], size: usize) -> Result<Vec<i32>, Error>
+{
+    let malformed = || invalid_data("malformed compressed window data".to_string());
+    let read_u32 = |ofs: usize| -> Result<u32, Error> {
+        let bytes = payload.get(ofs..ofs.saturating_add(4)).ok_or_else(malformed)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    let num_blocks = read_u32(0)? as usize;
+
+    /* Each block holds at most BLOCK_SIZE values and has an 8 bytes header */
+    if num_blocks > payload.len() / 8 || size > num_blocks.saturating_mul(BLOCK_SIZE)
+    {
+        return Err(malformed());
+    }
+
+    let mut ret = Vec::with_capacity(size);
+    let mut ofs = 4;
+
+    for _ in 0..num_blocks
+    {
+        let raw_len = read_u32(ofs)? as usize;
+        let compressed_len = read_u32(ofs + 4)? as usize;
+        ofs += 8;
+
+        if raw_len > BLOCK_SIZE * MAX_VARINT_LEN
+        {
+            return Err(malformed());
+        }
+
+        let compressed = payload.get(ofs..ofs.saturating_add(compressed_len)).ok_or_else(malformed)?;
+        decode_varints(&inflate_block(compressed, raw_len)?[0..], &mut ret)?;
+        ofs += compressed_len;
+
+        if ret.len() > size { return Err(malformed()); }
+    }
+
+    if ofs != payload.len() || ret.len() != size
+    {
+        return Err(malformed());
+    }
+
+    return Ok(ret);
+}
+
+fn crc32_table() -> [u32; 256]
+{
+    let mut table = [0u32; 256];
+    for i in 0..256
+    {
+        let mut c = i as u32;
+        for _ in 0..8
+        {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        table[i] = c;
+    }
+    return table;
+}
+
+struct Crc32 {
+    table: [u32; 256],
+    value: u32
+}
+
+impl Crc32 {
+    fn new() -> Self { Crc32 { table: crc32_table(), value: 0xffffffff } }
+
+    fn update(&mut self, data: &[u8])
+    {
+        for byte in data
+        {
+            self.value = self.table[((self.value ^ (*byte as u32)) & 0xff) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    fn get(&self) -> u32 { self.value ^ 0xffffffff }
+}
+
+pub fn crc32(data: &[u8]) -> u32
+{
+    let mut crc = Crc32::new();
+    crc.update(data);
+    return crc.get();
+}
+
+pub fn invalid_data(msg: String) -> Error
+{
+    return Error::new(ErrorKind::InvalidData, msg);
+}
+
+pub struct DumpWriter<'a, W:Write> {
+    fp : &'a mut W,
+    crc: Crc32,
+    encoding: DumpEncoding
+}
+
+impl <'a, W:Write> DumpWriter<'a, W> {
+    pub fn new(fp: &'a mut W, encoding: DumpEncoding) -> Result<Self, Error>
+    {
+        let mut ret = DumpWriter { fp, crc: Crc32::new(), encoding };
+        ret.write_bytes(DUMP_MAGIC)?;
+        ret.write_u32(DUMP_VERSION)?;
+        return Ok(ret);
+    }
+
+    pub fn encoding(&self) -> DumpEncoding { self.encoding }
+
+    pub fn write_bytes(&mut self, data: &[u8]) -> Result<(), Error>
+    {
+        self.crc.update(data);
+        return self.fp.write_all(data);
+    }
+
+    pub fn write_u32(&mut self, val: u32) -> Result<(), Error> { self.write_bytes(&val.to_le_bytes()) }
+
+    pub fn write_u64(&mut self, val: u64) -> Result<(), Error> { self.write_bytes(&val.to_le_bytes()) }
+
+    pub fn write_i32_slice(&mut self, data: &[i32]) -> Result<(), Error>
+    {
+        let mut buf = Vec::with_capacity(data.len() * 4);
+        data.iter().for_each(|v| buf.extend_from_slice(&v.to_le_bytes()));
+        return self.write_bytes(&buf[0..]);
+    }
+
+    /* Write the checksum, nothing should be written after this */
+    pub fn finish(self) -> Result<(), Error>
+    {
+        let crc = self.crc.get();
+        return self.fp.write_all(&crc.to_le_bytes());
+    }
+}
+
+pub struct DumpReader<'a, R:Read> {
+    fp : &'a mut R,
+    crc: Crc32,
+}
+
+impl <'a, R:Read> DumpReader<'a, R> {
+    pub fn new(fp: &'a mut R) -> Result<Self, Error>
+    {
+        let mut ret = DumpReader { fp, crc: Crc32::new() };
+
+        let mut magic = [0u8; 8];
+        ret.read_bytes(&mut magic)?;
+
+        if &magic != DUMP_MAGIC
+        {
+            return Err(invalid_data("not a Limo scanner dump, it may be produced by a legacy version of Limo and needs to be regenerated".to_string()));
+        }
+
+        let version = ret.read_u32()?;
+
+        if version != DUMP_VERSION
+        {
+            return Err(invalid_data(format!("unsupported scanner dump version {} (expected {})", version, DUMP_VERSION)));
+        }
+
+        return Ok(ret);
+    }
+
+    pub fn read_vec(&mut self, size: usize) -> Result<Vec<u8>, Error>
+    {
+        let mut ret = Vec::with_capacity(size.min(READ_CHUNK_SIZE));
+
+        while ret.len() < size
+        {
+            let begin = ret.len();
+            ret.resize(begin + (size - begin).min(READ_CHUNK_SIZE), 0);
+            self.read_bytes(&mut ret[begin..])?;
+        }
+
+        return Ok(ret);
+    }
+
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), Error>
+    {
+        self.fp.read_exact(buf).map_err(|e| if e.kind() == ErrorKind::UnexpectedEof {
+            invalid_data("the scanner dump is truncated".to_string())
+        } else { e })?;
+        self.crc.update(buf);
+        return Ok(());
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error>
+    {
+        let mut buf = [0u8; 4];
+        self.read_bytes(&mut buf)?;
+        return Ok(u32::from_le_bytes(buf));
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error>
+    {
+        let mut buf = [0u8; 8];
+        self.read_bytes(&mut buf)?;
+        return Ok(u64::from_le_bytes(buf));
+    }
+
+    pub fn read_i32_slice(&mut self, data: &mut [i32]) -> Result<(), Error>
+    {
+        let mut buf = vec![0u8; data.len() * 4];
+        self.read_bytes(&mut buf[0..])?;
+        for (val, bytes) in data.iter_mut().zip(buf.chunks_exact(4))
+        {
+            *val = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        return Ok(());
+    }
+
+    /* Read and verify the checksum, this should be called after the last section is read */
+    pub fn finish(self) -> Result<(), Error>
+    {
+        let expected = self.crc.get();
+        let mut buf = [0u8; 4];
+        self.fp.read_exact(&mut buf).map_err(|_| invalid_data("the scanner dump is truncated".to_string()))?;
+
+        if u32::from_le_bytes(buf) != expected
+        {
+            return Err(invalid_data("checksum mismatch, the scanner dump is corrupted".to_string()));
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod dump_test {
+    use super::*;
+
+    #[test]
+    fn test_crc32()
+    {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.get(), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), Error>
+    {
+        let mut buf = Vec::new();
+        let mut writer = DumpWriter::new(&mut buf, DumpEncoding::Raw)?;
+        writer.write_u32(42)?;
+        writer.write_i32_slice(&[1, -2, 3])?;
+        writer.finish()?;
+
+        let mut data = &buf[0..];
+        let mut reader = DumpReader::new(&mut data)?;
+        let mut values = [0i32; 3];
+        assert_eq!(reader.read_u32()?, 42);
+        reader.read_i32_slice(&mut values)?;
+        reader.finish()?;
+        assert_eq!(values, [1, -2, 3]);
+
+        buf[13] ^= 1;
+        let mut data = &buf[0..];
+        let mut reader = DumpReader::new(&mut data)?;
+        reader.read_u32()?;
+        reader.read_i32_slice(&mut values)?;
+        assert!(reader.finish().is_err());
+
+        let mut legacy = &[3u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0][0..];
+        assert!(DumpReader::new(&mut legacy).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression() -> Result<(), Error>
+    {
+        let data:Vec<i32> = (0..(BLOCK_SIZE as i32 + 100)).map(|x| if x % 7 == 0 { x % 300 - 150 } else { 0 }).chain(vec![i32::max_value(), i32::min_value()]).collect();
+
+        let payload = compress_i32(&data[0..])?;
+        assert!(payload.len() < data.len());
+        assert_eq!(decompress_i32(&payload[0..], data.len())?, data);
+        assert!(decompress_i32(&payload[0..], data.len() + 1).is_err());
+        assert!(decompress_i32(&payload[0..payload.len() - 1], data.len()).is_err());
+
+        Ok(())
+    }
+}
@@ -7,7 +7,7 @@ pub struct EventPairProc<'a, DM : DepthModel> {
     last_pos : u32,
     recent   : Vec<Event<'a, DM>>,
     max_copy_num : u32,
-    window_size: u32,
+    score_threshold: DM::Output,
     fe_iter  : FrontendIter<'a, DM>,
     chrom_size: u32,
     last_mb: u32,
@@ -25,7 +25,7 @@ impl <'a, DM : DepthModel> EventPairProc<'a, DM>
             recent    : Vec::new(),
             max_copy_num,
             fe_iter   : fe.iter(),
-            window_size: fe.get_window_size(),
+            score_threshold: fe.get_score_threshold(),
             chrom_size: fe.get_chrom_size(),
             last_mb: 0,
         };
@@ -48,7 +48,14 @@ impl <'a, DM : DepthModel> Iterator for EventPairProc<'a, DM>
                     debug!("Chrom {}: Pairing model events - {}MB/{}MB", next_event.chrom, self.last_mb * 10, self.chrom_size / 100_0000);
                 }
 
-                if next_event.copy_num > self.max_copy_num { continue; }
+                if next_event.copy_num == u32::max_value() { continue; }
+
+                /* The models estimating the copy number may go beyond the target copy numbers */
+                if next_event.copy_num > self.max_copy_num
+                {
+                    self.max_copy_num = next_event.copy_num;
+                    self.left_side.resize((self.max_copy_num + 1) as usize, None);
+                }
                 if self.recent.len() == 3 { self.recent.remove(0); }
                 self.recent.push(next_event);
 
@@ -72,8 +79,8 @@ impl <'a, DM : DepthModel> Iterator for EventPairProc<'a, DM>
                            Side::Right => {
                                if let Some(ref left_side) = self.left_side[cur_cn]
                                {
-                                   if DM::score_threshold(self.window_size, Clone::clone(&left_side.score)) && 
-                                      DM::score_threshold(self.window_size, Clone::clone(&current.score))
+                                   if DM::score_threshold(Clone::clone(&self.score_threshold), Clone::clone(&left_side.score)) && 
+                                      DM::score_threshold(Clone::clone(&self.score_threshold), Clone::clone(&current.score))
                                    {
                                        ret = Some((Clone::clone(left_side), Clone::clone(current)));
                                    }
@@ -2,6 +2,23 @@ use crate::scanner::Scanner;
 use crate::depth_model::DepthModel;
 use crate::histogram::Histogram;
 use crate::window::WindowIter;
+use crate::gc::GcCorrection;
+
+/* The calibration falls back to the default threshold with fewer samples than this */
+const MIN_CALIBRATION_SAMPLES: usize = 1000;
+/* The normalized depth of a copy neutral position is within this distance to 1.0 */
+const NEUTRAL_DEPTH_TOLERANCE: f64 = 0.25;
+
+/* How the score threshold of the event pairing is decided */
+#[derive(Debug, Copy, Clone)]
+pub enum ScoreThreshold<T> {
+    /* The fixed function of the window size given by the depth model */
+    Fixed,
+    /* Calibrated from the copy neutral regions with the target false positive rate */
+    Calibrated(f64),
+    /* Given by the user */
+    Value(T),
+}
 
 #[derive(Debug, Copy, Clone)]
 pub enum Side {
@@ -19,7 +36,9 @@ pub struct Frontend<DM:DepthModel + Sized> {
     scanner : Scanner,
     window_size: u32,
     copy_nums: Vec<u32>,
-    dmp      : DM::ParamType
+    dmp      : DM::ParamType,
+    score_threshold: DM::Output,
+    gc_correction: Option<GcCorrection>
 }
 
 pub struct FrontendIter<'a, DM:DepthModel + Sized> {
@@ -28,6 +47,10 @@ pub struct FrontendIter<'a, DM:DepthModel + Sized> {
     correct_iter: WindowIter<'a, i32, i32>,
     exclude_iter: WindowIter<'a, i32, i32>,
     hist    : Histogram,
+    last_dep: f64,
+    gc_correction: Option<&'a GcCorrection>,
+    /* The position of the window the next depth comes from */
+    dep_pos : u32,
     left_mod: Vec<SVModel<DM>>,
     right_mod: Vec<SVModel<DM>>
 }
@@ -39,6 +62,8 @@ pub struct Event<'a, DM:DepthModel> {
     pub score: DM::Output,
     pub pos  : u32,
     pub copy_num: u32,
+    /* The depth level after the change, for the models estimating it */
+    pub level: Option<f64>,
     pub total_dep: u32,
     pub lowmq_dep: u32
 }
@@ -52,7 +77,9 @@ impl <DM:DepthModel + Sized> Frontend<DM> {
             scanner,
             window_size,
             copy_nums:copy_nums.to_vec(),
-            dmp
+            dmp,
+            score_threshold: DM::default_score_threshold(window_size),
+            gc_correction: None
         };
 
         return Ok(ret);
@@ -77,6 +104,67 @@ impl <DM:DepthModel + Sized> Frontend<DM> {
     {
         return FrontendIter::new(self);
     }
+
+    pub fn get_gc_correction(&self) -> Option<&GcCorrection> { self.gc_correction.as_ref() }
+
+    /* The normalized depth is divided by the expected depth of the GC content from now on */
+    pub fn set_gc_correction(&mut self, correction: GcCorrection) { self.gc_correction = Some(correction); }
+
+    pub fn get_score_threshold(&self) -> DM::Output { self.score_threshold.clone() }
+
+    /* Decide the score threshold used by the event pairing and return it */
+    pub fn set_score_threshold(&mut self, how: ScoreThreshold<DM::Output>) -> DM::Output
+    {
+        self.score_threshold = match how {
+            ScoreThreshold::Fixed => DM::default_score_threshold(self.window_size),
+            ScoreThreshold::Value(threshold) => threshold,
+            ScoreThreshold::Calibrated(rate) => self.calibrate_score_threshold(rate).unwrap_or_else(|| DM::default_score_threshold(self.window_size)),
+        };
+
+        return self.score_threshold.clone();
+    }
+
+    /* In the copy neutral regions any event passing the threshold is a false positive, so the
+     * threshold is the quantile of the local minimal scores in these regions at the target
+     * false positive rate. A position is taken only if all the depth seen by the models is
+     * neutral. None if the chromosome doesn't have enough neutral regions */
+    pub fn calibrate_score_threshold(&self, false_positive_rate: f64) -> Option<DM::Output>
+    {
+        let model_len = self.get_scan_size();
+        let mut iter = self.iter();
+        let mut neutral_len = 0;
+        let mut recent = Vec::<DM::Output>::with_capacity(3);
+        let mut scores = Vec::new();
+
+        while let Some(event) = iter.next()
+        {
+            if (iter.get_last_depth() - 1.0).abs() < NEUTRAL_DEPTH_TOLERANCE { neutral_len += 1; } else { neutral_len = 0; }
+
+            if neutral_len < model_len || event.copy_num == u32::max_value()
+            {
+                recent.clear();
+                continue;
+            }
+
+            if recent.len() == 3 { recent.remove(0); }
+            recent.push(event.score);
+
+            if recent.len() == 3 &&
+               DM::score_cmp(recent[1].clone(), recent[0].clone()) < 0 &&
+               DM::score_cmp(recent[1].clone(), recent[2].clone()) < 0
+            {
+                scores.push(recent[1].clone());
+            }
+        }
+
+        if scores.len() < MIN_CALIBRATION_SAMPLES { return None; }
+
+        scores.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        let idx = ((scores.len() as f64 * false_positive_rate) as usize).min(scores.len() - 1);
+
+        return Some(scores[idx].clone());
+    }
 }
 
 impl <'a, DM:DepthModel + Sized> FrontendIter<'a, DM>
@@ -87,13 +175,21 @@ impl <'a, DM:DepthModel + Sized> FrontendIter<'a, DM>
         {
             if let Some(excluded) = self.exclude_iter.next()
             {
-                let normalized = self.hist.normalize((correct - excluded) as u32);
+                let mut normalized = self.hist.normalize((correct - excluded) as u32);
+                if let Some(gc) = self.gc_correction {
+                    normalized /= gc.factor(self.dep_pos);
+                }
+                self.dep_pos += 1;
+                self.last_dep = normalized;
                 return Some((From::from(normalized), correct as u32, excluded as u32));
             }
         }
         return None;
     }
 
+    /* The normalized depth of the last event */
+    pub fn get_last_depth(&self) -> f64 { self.last_dep }
+
     fn feed(&mut self, dep : DM::Input)
     {
         self.left_mod[0..].iter_mut().for_each(|m| m.model.put(dep));
@@ -113,7 +209,7 @@ impl <'a, DM:DepthModel + Sized> FrontendIter<'a, DM>
         let mut right_mod = Vec::<SVModel<DM>>::new();
         
 
-        for copy_num in obj.copy_nums[0..].iter()
+        for copy_num in DM::model_copy_nums(&obj.copy_nums[0..]).iter()
         {
             let left_side = DM::create_model(*copy_num, true, obj.dmp);
             let right_side = DM::create_model(*copy_num, false, obj.dmp);
@@ -133,6 +229,9 @@ impl <'a, DM:DepthModel + Sized> FrontendIter<'a, DM>
         
         let mut ret = Self {
             hist,
+            last_dep: 0.0,
+            gc_correction: obj.gc_correction.as_ref(),
+            dep_pos: 0,
             pos: size,
             left_mod,
             right_mod,
@@ -172,6 +271,7 @@ impl <'a, DM : DepthModel + Sized> Iterator for FrontendIter<'a, DM>
             let mut best_score = Default::default(); 
             let mut best_side: Option<Side> = None;
             let mut best_copy_num: Option<u32> = None;
+            let mut best_level: Option<f64> = None;
 
             let mut scan_models = |model : &SVModel<DM>| {
                 if best_side.is_some()
@@ -180,14 +280,16 @@ impl <'a, DM : DepthModel + Sized> Iterator for FrontendIter<'a, DM>
                     if best_score > cur_score 
                     {
                         best_score = cur_score;
-                        best_copy_num = Some(model.copy_num);
+                        best_copy_num = Some(model.model.estimated_copy_num().unwrap_or(model.copy_num));
+                        best_level = model.model.estimated_level();
                         best_side = Some(model.side);
                     }
                 }
                 else
                 {
                     best_score = model.model.get_score();
-                    best_copy_num = Some(model.copy_num);
+                    best_copy_num = Some(model.model.estimated_copy_num().unwrap_or(model.copy_num));
+                    best_level = model.model.estimated_level();
                     best_side = Some(model.side);
                 }
             };
@@ -202,6 +304,7 @@ impl <'a, DM : DepthModel + Sized> Iterator for FrontendIter<'a, DM>
                     pos,
                     side : best_side.unwrap(),
                     copy_num: best_copy_num.unwrap(),
+                    level: best_level,
                     total_dep, lowmq_dep,
                     chrom: self.chrom
                 });
@@ -213,6 +316,7 @@ impl <'a, DM : DepthModel + Sized> Iterator for FrontendIter<'a, DM>
                     pos,
                     side  : Side::Left,
                     copy_num: u32::max_value(),
+                    level: None,
                     total_dep, lowmq_dep,
                     chrom: self.chrom
                 });
@@ -225,4 +329,37 @@ impl <'a, DM : DepthModel + Sized> Iterator for FrontendIter<'a, DM>
 #[cfg(test)]
 mod test {
     //use crate::scanner::mock_bam::*;
+    use super::*;
+    use crate::coverage::CoverageReader;
+    use crate::models::linear::LinearModel;
+    use crate::test_util::TempFile;
+
+    #[test]
+    fn test_calibrate_score_threshold()
+    {
+        let file = TempFile::new("frontend-coverage");
+        let path = file.path();
+
+        /* A copy neutral chromosome with some noise */
+        let mut seed = 1u32;
+        let lines:Vec<_> = (0..50000).map(|i| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            format!("chr1\t{}\t{}\t{}\n", i * 10, i * 10 + 10, 27 + (seed >> 16) % 7)
+        }).collect();
+        std::fs::write(path, lines.concat()).unwrap();
+
+        let contigs = vec![("chr1".to_string(), 500000)];
+        let mut reader = CoverageReader::open(path, &contigs).unwrap();
+        let scanner = Scanner::from_coverage("chr1", 500000, 100, &mut reader, None).unwrap();
+
+        let mut frontend = Frontend::<LinearModel>::new(scanner, 300, &[1, 3], None).unwrap();
+
+        assert_eq!(frontend.get_score_threshold(), LinearModel::default_score_threshold(300));
+
+        let loose = frontend.calibrate_score_threshold(0.1).unwrap();
+        let strict = frontend.set_score_threshold(ScoreThreshold::Calibrated(0.001));
+        assert!(strict > 0.0 && strict <= loose);
+
+        assert_eq!(frontend.set_score_threshold(ScoreThreshold::Value(1.0)), 1.0);
+    }
 }
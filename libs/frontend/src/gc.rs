@@ -0,0 +1,272 @@
+/* The GC-content bias correction. The GC fraction of each window is computed from the indexed
+ * reference FASTA, and the median depth of the windows with the same GC percentage makes the
+ * GC-to-depth curve of the sample. The curve is fitted once before the chromosomes are called,
+ * from the depth of the ranges sampled across the genome, so a large event or a short contig
+ * doesn't skew its own correction. The normalized depth is then divided by the curve, so the
+ * GC-extreme regions don't look like gains or losses. */
+use crate::scanner::Scanner;
+use crate::reference::Reference;
+
+use std::collections::HashMap;
+
+use log::{debug, warn};
+
+/* The GC percentages with fewer windows than this aren't corrected */
+const MIN_WINDOWS_PER_GC: usize = 100;
+/* The windows with more N bases than this fraction don't have a GC fraction */
+const MAX_N_FRACTION: f64 = 0.5;
+/* The number of windows fetched from the reference at once */
+const FETCH_WINDOWS: u32 = 4096;
+/* The depth is sampled from this many ranges spread over the genome */
+const SAMPLE_RANGES: u64 = 200;
+const SAMPLE_RANGE_SIZE: u32 = 1_000_000;
+/* The GC percentage of the windows mostly made of N */
+const NO_GC: u8 = u8::MAX;
+
+/* The GC percentage of each window of [begin, end) */
+fn gc_percents(reference: &Reference, chrom: &str, begin: u32, end: u32, bin_size: u32) -> Result<Vec<u8>, ()>
+{
+    let mut ret = Vec::with_capacity(((end - begin) / bin_size + 1) as usize);
+    let mut pos = begin;
+
+    while pos < end
+    {
+        let next = (pos + bin_size * FETCH_WINDOWS).min(end);
+        let seq = reference.fetch(chrom, pos, next)?;
+
+        for window in seq.chunks(bin_size as usize)
+        {
+            let (gc, at) = window.iter().fold((0, 0), |(gc, at), base| match base {
+                b'G' | b'C' | b'g' | b'c' => (gc + 1, at),
+                b'A' | b'T' | b'a' | b't' => (gc, at + 1),
+                _ => (gc, at)
+            });
+
+            ret.push(if ((gc + at) as f64) < (1.0 - MAX_N_FRACTION) * window.len() as f64 { NO_GC } else {
+                (gc as f64 * 100.0 / (gc + at) as f64).round() as u8
+            });
+        }
+
+        pos = next;
+    }
+
+    return Ok(ret);
+}
+
+/* The ranges of the depth samples, evenly spread over the contigs and aligned to the windows */
+fn sample_ranges(contigs: &[(String, usize)], bin_size: u32) -> Vec<(&str, u32, u32)>
+{
+    let total = contigs.iter().fold(0u64, |s, (_, len)| s + *len as u64);
+    let step = (total / SAMPLE_RANGES).max(SAMPLE_RANGE_SIZE as u64);
+
+    let mut ret = Vec::new();
+    let mut contig_begin = 0u64;
+
+    for (name, len) in contigs
+    {
+        let len = *len as u64;
+        let mut pos = (contig_begin + step - 1) / step * step;
+
+        while pos < contig_begin + len
+        {
+            let begin = ((pos - contig_begin) as u32) / bin_size * bin_size;
+            let end = (begin as u64 + SAMPLE_RANGE_SIZE as u64).min(len) as u32;
+            ret.push((name.as_str(), begin, end));
+            pos += step;
+        }
+
+        contig_begin += len;
+    }
+
+    return ret;
+}
+
+/* The depth of each window of the scanned range, excluding the low MAPQ reads like the frontend
+ * does. It's not normalized, so the samples from different ranges are comparable */
+pub fn window_depth(scanner: &Scanner, bin_size: u32) -> Vec<f64>
+{
+    let bin_size = bin_size as usize;
+    let correct = scanner.get_corrected().iter(bin_size).step_by(bin_size);
+    let excluded = scanner.get_low_mq_window().iter(bin_size).step_by(bin_size);
+
+    return correct.zip(excluded).map(|(c, e):(i32, i32)| (c - e).max(0) as f64).collect();
+}
+
+/* The GC-to-depth curve of the sample, shared by all the chromosomes */
+#[derive(Debug, Clone)]
+pub struct GcCurve {
+    bin_size: u32,
+    /* The median depth of each GC percentage 0..=100 relative to the overall median */
+    curve   : Vec<f64>,
+    /* The GC percentage of each window of the corrected contigs, NO_GC for the windows mostly made of N */
+    percents: HashMap<String, Vec<u8>>,
+}
+
+impl GcCurve {
+    /* Fit the curve from the depth sampled over the sampled contigs, sample_depth(chrom, begin, end)
+     * gives the window depth of the range. The reference is opened once, and the GC percentages of
+     * the corrected contigs are kept for the correction of each chromosome */
+    pub fn fit<F>(reference: &str, sampled: &[(String, usize)], corrected: &[(String, usize)], bin_size: u32, mut sample_depth: F) -> Result<Self, ()>
+        where F: FnMut(&str, u32, u32) -> Result<Vec<f64>, ()>
+    {
+        let reference = Reference::open(reference)?;
+
+        let mut percents = HashMap::new();
+
+        for (name, len) in corrected
+        {
+            if !percents.contains_key(name) {
+                percents.insert(name.clone(), gc_percents(&reference, name, 0, *len as u32, bin_size)?);
+            }
+        }
+
+        let mut depth = Vec::new();
+        let mut gc = Vec::new();
+
+        for (chrom, begin, end) in sample_ranges(sampled, bin_size)
+        {
+            let range_gc = if let Some(values) = percents.get(chrom) {
+                values[(begin / bin_size) as usize..].iter().take(((end - begin + bin_size - 1) / bin_size) as usize).cloned().collect()
+            } else {
+                gc_percents(&reference, chrom, begin, end, bin_size)?
+            };
+
+            let range_depth = sample_depth(chrom, begin, end)?;
+
+            depth.extend(range_depth.into_iter().take(range_gc.len()));
+            gc.extend(range_gc.into_iter().take(depth.len() - gc.len()));
+        }
+
+        let curve = Self::median_curve(&depth[0..], &gc[0..]);
+
+        debug!("The GC-to-depth curve of the sample from {} windows {:?}", depth.len(), curve);
+
+        return Ok(GcCurve { bin_size, curve, percents });
+    }
+
+    /* The curve is the median depth of each GC percentage relative to the overall median */
+    fn median_curve(depth: &[f64], gc: &[u8]) -> Vec<f64>
+    {
+        let fcmp = |a:&f64, b:&f64| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal);
+        let median = |values: &mut Vec<f64>| {
+            values.sort_unstable_by(fcmp);
+            values[values.len() / 2]
+        };
+
+        let mut by_gc = vec![Vec::new(); 101];
+        let mut all = Vec::new();
+
+        /* The empty windows are mostly the gaps, which don't tell the bias */
+        for (dep, gc) in depth.iter().zip(gc.iter())
+        {
+            if *gc != NO_GC && *dep > 0.0 {
+                by_gc[*gc as usize].push(*dep);
+                all.push(*dep);
+            }
+        }
+
+        if all.is_empty()
+        {
+            return vec![1.0; 101];
+        }
+
+        let overall = median(&mut all);
+
+        return by_gc.iter_mut().map(|values| {
+            if values.len() < MIN_WINDOWS_PER_GC { 1.0 } else { (median(values) / overall).max(0.1) }
+        }).collect();
+    }
+
+    /* The correction of the scanned range of the chromosome */
+    pub fn correction(&self, chrom: &str, offset: u32, size: u32) -> GcCorrection
+    {
+        let num_windows = (size / self.bin_size + 1) as usize;
+
+        let percents = if let Some(percents) = self.percents.get(chrom) { percents } else {
+            warn!("Chrom {}: The GC content isn't available, the depth isn't corrected", chrom);
+            return GcCorrection { bin_size: self.bin_size, factors: vec![1.0; num_windows] };
+        };
+
+        let factors = (0..num_windows).map(|idx| {
+            let gc = percents.get(((offset + idx as u32 * self.bin_size) / self.bin_size) as usize).cloned().unwrap_or(NO_GC);
+            if gc == NO_GC { 1.0 } else { self.curve[gc as usize] }
+        }).collect();
+
+        return GcCorrection { bin_size: self.bin_size, factors };
+    }
+}
+
+/* The correction of a chromosome by the curve of the sample */
+#[derive(Debug, Clone)]
+pub struct GcCorrection {
+    bin_size: u32,
+    /* The expected depth of each window relative to the average */
+    factors : Vec<f64>,
+}
+
+impl GcCorrection {
+    /* The expected depth of the window starting at the position relative to the average, the
+     * normalized depth is divided by it */
+    pub fn factor(&self, pos: u32) -> f64
+    {
+        return self.factors.get((pos / self.bin_size) as usize).cloned().unwrap_or(1.0);
+    }
+}
+
+#[cfg(test)]
+mod gc_test {
+    use super::*;
+
+    #[test]
+    fn test_gc_curve()
+    {
+        /* The depth grows with the GC fraction, 0.8 at 30% and 1.2 at 60% */
+        let mut depth = Vec::new();
+        let mut gc = Vec::new();
+
+        for i in 0..1000
+        {
+            let (percent, dep) = if i % 2 == 0 { (30, 0.8) } else { (60, 1.2) };
+            depth.push(dep + if i % 4 < 2 { 0.01 } else { -0.01 });
+            gc.push(percent);
+        }
+
+        /* Too few windows and a gap */
+        depth.extend_from_slice(&[2.0, 0.0]);
+        gc.extend_from_slice(&[90, NO_GC]);
+
+        let curve = GcCurve {
+            bin_size: 100,
+            curve: GcCurve::median_curve(&depth[0..], &gc[0..]),
+            percents: vec![("chr1".to_string(), gc.clone())].into_iter().collect(),
+        };
+
+        let correction = curve.correction("chr1", 0, 100200);
+
+        assert!((correction.factor(0) - 0.81 / 1.19).abs() < 1e-6);
+        assert!((correction.factor(150) - 1.21 / 1.19).abs() < 1e-6);
+        assert_eq!(correction.factor(100000), 1.0);
+        assert_eq!(correction.factor(100100), 1.0);
+        assert_eq!(correction.factor(200000), 1.0);
+
+        /* The windows of a range are looked up from its offset in the chromosome */
+        let correction = curve.correction("chr1", 100, 1000);
+        assert!((correction.factor(0) - 1.21 / 1.19).abs() < 1e-6);
+
+        assert_eq!(curve.correction("chr2", 0, 1000).factor(0), 1.0);
+    }
+
+    #[test]
+    fn test_sample_ranges()
+    {
+        let contigs = vec![("chr1".to_string(), 300_000_000), ("chr2".to_string(), 100_000_000), ("chrM".to_string(), 16_569)];
+        let ranges = sample_ranges(&contigs[0..], 300);
+
+        /* One range every 2000082 bases of the genome */
+        assert_eq!(ranges.len(), 201);
+        assert_eq!(ranges.iter().filter(|(chrom, _, _)| *chrom == "chr1").count(), 150);
+        assert!(ranges.iter().all(|(_, begin, end)| begin % 300 == 0 && end - begin <= SAMPLE_RANGE_SIZE));
+        assert_eq!(ranges[150], ("chr2", 12300, 1012300));
+        assert_eq!(ranges[200], ("chrM", 16200, 16569));
+    }
+}
@@ -0,0 +1,220 @@
+use super::hts::*;
+use std::ffi::{CString, CStr};
+use std::ptr::null_mut;
+use std::slice::from_raw_parts;
+
+use log::error;
+
+/* A @RG record of the header */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ReadGroup {
+    pub id       : String,
+    pub sample   : Option<String>,
+    pub library  : Option<String>,
+    pub platform : Option<String>,
+}
+
+/* A @PG record of the header */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub id          : String,
+    pub name        : Option<String>,
+    pub version     : Option<String>,
+    pub command_line: Option<String>,
+}
+
+/* The header of an alignment file, it owns the htslib header and releases it on drop */
+pub struct BamHeader {
+    hdr : *mut bam_hdr_t
+}
+
+impl Drop for BamHeader {
+    fn drop(&mut self)
+    {
+        if self.hdr != null_mut()
+        {
+            unsafe { bam_hdr_destroy(self.hdr) };
+            self.hdr = null_mut();
+        }
+    }
+}
+
+/* Each header line of the record type as a list of (tag, value) */
+fn parse_records<'a>(text: &'a str, record_type: &str) -> Vec<Vec<(&'a str, &'a str)>>
+{
+    return text.lines()
+        .filter(|line| line.split('\t').next() == Some(record_type))
+        .map(|line| line.split('\t').skip(1).filter_map(|field| {
+            if field.len() > 3 && field.as_bytes()[2] == b':' { Some((&field[..2], &field[3..])) } else { None }
+        }).collect())
+        .collect();
+}
+
+fn get_tag(record: &[(&str, &str)], tag: &str) -> Option<String>
+{
+    return record.iter().find(|(t, _)| *t == tag).map(|(_, v)| v.to_string());
+}
+
+fn parse_read_groups(text: &str) -> Vec<ReadGroup>
+{
+    return parse_records(text, "@RG").iter().map(|record| ReadGroup {
+        id      : get_tag(record, "ID").unwrap_or_default(),
+        sample  : get_tag(record, "SM"),
+        library : get_tag(record, "LB"),
+        platform: get_tag(record, "PL"),
+    }).collect();
+}
+
+fn parse_programs(text: &str) -> Vec<Program>
+{
+    return parse_records(text, "@PG").iter().map(|record| Program {
+        id          : get_tag(record, "ID").unwrap_or_default(),
+        name        : get_tag(record, "PN"),
+        version     : get_tag(record, "VN"),
+        command_line: get_tag(record, "CL"),
+    }).collect();
+}
+
+impl BamHeader {
+    /* Take the ownership of a header returned by sam_hdr_read */
+    pub(crate) fn from_raw(hdr: *mut bam_hdr_t) -> Self
+    {
+        return BamHeader { hdr };
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut bam_hdr_t { self.hdr }
+
+    /* Read the header of the alignment file */
+    pub fn read(path: &str, reference: Option<&str>) -> Result<Self, ()>
+    {
+        let fp = unsafe {
+            hts_open(CString::new(path).unwrap().as_ptr(),
+                     CString::new("rb").unwrap().as_ptr())
+        };
+
+        if fp == null_mut()
+        {
+            error!("Cannot open the alignment file");
+            return Err(());
+        }
+
+        if let Some(reference) = reference
+        {
+            if unsafe{ hts_set_fai_filename(fp, CString::new(reference).unwrap().as_ptr()) } < 0
+            {
+                error!("Cannot find the reference file.");
+                unsafe { hts_close(fp) };
+                return Err(());
+            }
+        }
+
+        let hdr = unsafe { sam_hdr_read(fp) };
+
+        unsafe { hts_close(fp) };
+
+        if hdr == null_mut()
+        {
+            error!("Cannot read the header from the alignment file");
+            return Err(());
+        }
+
+        return Ok(BamHeader::from_raw(hdr));
+    }
+
+    pub fn num_contigs(&self) -> usize
+    {
+        return unsafe { (*self.hdr).n_targets } as usize;
+    }
+
+    pub fn contig_name(&self, id: usize) -> Option<String>
+    {
+        if id >= self.num_contigs() { return None; }
+        let raw_name = unsafe { CStr::from_ptr(*(*self.hdr).target_name.add(id) as *const i8) };
+        return Some(raw_name.to_string_lossy().to_string());
+    }
+
+    pub fn contig_length(&self, id: usize) -> Option<usize>
+    {
+        if id >= self.num_contigs() { return None; }
+        return Some(unsafe { *(*self.hdr).target_len.add(id) } as usize);
+    }
+
+    /* The (name, length) of all the contigs, in the header order */
+    pub fn contigs(&self) -> Vec<(String, usize)>
+    {
+        let raw_names = unsafe{ from_raw_parts((*self.hdr).target_name, self.num_contigs()) };
+        let raw_lens = unsafe{ from_raw_parts((*self.hdr).target_len, self.num_contigs()) };
+
+        return raw_names.iter().zip(raw_lens).map(|(raw_name, len)| {
+            let raw_name = unsafe{ CStr::from_ptr(*raw_name as *const i8) };
+            (raw_name.to_string_lossy().to_string(), *len as usize)
+        }).collect();
+    }
+
+    /* The plain text part of the header, which includes the @SQ, @RG and @PG lines */
+    pub fn text(&self) -> String
+    {
+        let text = unsafe {
+            if (*self.hdr).text == null_mut() { &[][..] }
+            else { from_raw_parts((*self.hdr).text as *const u8, (*self.hdr).l_text as usize) }
+        };
+
+        /* The text may be NUL terminated */
+        let text = text.split(|c| *c == 0).next().unwrap_or(&[]);
+
+        return String::from_utf8_lossy(text).to_string();
+    }
+
+    pub fn read_groups(&self) -> Vec<ReadGroup>
+    {
+        return parse_read_groups(&self.text());
+    }
+
+    pub fn programs(&self) -> Vec<Program>
+    {
+        return parse_programs(&self.text());
+    }
+
+    /* The distinct sample names of the read groups, in the header order */
+    pub fn samples(&self) -> Vec<String>
+    {
+        let mut ret = Vec::<String>::new();
+
+        for sample in self.read_groups().into_iter().filter_map(|rg| rg.sample)
+        {
+            if !ret.contains(&sample) { ret.push(sample); }
+        }
+
+        return ret;
+    }
+}
+
+#[cfg(test)]
+mod header_test {
+    use super::*;
+
+    const TEXT: &str = "@HD\tVN:1.6\tSO:coordinate\n\
+                        @SQ\tSN:chr1\tLN:248956422\n\
+                        @RG\tID:rg1\tSM:NA12878\tLB:lib1\tPL:ILLUMINA\n\
+                        @RG\tID:rg2\tSM:NA12878\n\
+                        @PG\tID:bwa\tPN:bwa\tVN:0.7.17\tCL:bwa mem ref.fa r1.fq r2.fq\n";
+
+    #[test]
+    fn test_parse_header()
+    {
+        let rgs = parse_read_groups(TEXT);
+        assert_eq!(rgs.len(), 2);
+        assert_eq!(rgs[0], ReadGroup {
+            id: "rg1".to_string(),
+            sample: Some("NA12878".to_string()),
+            library: Some("lib1".to_string()),
+            platform: Some("ILLUMINA".to_string())
+        });
+        assert_eq!(rgs[1].library, None);
+
+        let pgs = parse_programs(TEXT);
+        assert_eq!(pgs.len(), 1);
+        assert_eq!(pgs[0].name.as_ref().map(|x| x.as_str()), Some("bwa"));
+        assert_eq!(pgs[0].command_line.as_ref().map(|x| x.as_str()), Some("bwa mem ref.fa r1.fq r2.fq"));
+    }
+}
@@ -39,6 +39,22 @@ impl Histogram {
         return *self.average.as_mut().unwrap();
     }
 
+    /* The mean and variance of the values not lower than min, so that the empty windows in
+     * the gaps of the assembly can be left out */
+    pub fn get_moments(&self, min:u32) -> (f64, f64)
+    {
+        let (count, sum, square) = self.freq.iter().zip(0..).skip(min as usize).fold((0f64, 0f64, 0f64), |(c, s, q), (a, v)| {
+            let (a, v) = (*a as f64, v as f64);
+            (c + a, s + a * v, q + a * v * v)
+        });
+
+        if count == 0.0 { return (0.0, 0.0); }
+
+        let mean = sum / count;
+
+        return (mean, square / count - mean * mean);
+    }
+
     pub fn normalize(&mut self, val:u32) -> f64
     {
         if val as usize > self.freq.len()
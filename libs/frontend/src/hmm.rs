@@ -0,0 +1,168 @@
+/* The HMM segmentation of the depth. Instead of pairing the left and right events found by the
+ * depth model independently, the chromosome is cut into bins of the window size and each bin
+ * is assigned a copy number state by the Viterbi decoding. So the adjacent segments with
+ * different copy numbers, such as CN1 next to CN0, are found as they are. */
+use crate::scanner::Scanner;
+use crate::histogram::Histogram;
+use crate::gc::GcCorrection;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HmmParam {
+    /* The probability of switching to another state between two adjacent bins */
+    pub switch_prob: f64,
+}
+
+impl Default for HmmParam {
+    fn default() -> Self
+    {
+        return HmmParam { switch_prob: 1e-4 };
+    }
+}
+
+/* A run of bins in the same non-neutral state, [begin, end) in the scanner coordinates */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub begin: u32,
+    pub end  : u32,
+    pub copy_num: u32,
+}
+
+/* The normalized depth of each bin, excluding the low MAPQ reads like the frontend does */
+pub(crate) fn bin_depth(scanner: &Scanner, bin_size: u32) -> Vec<f64>
+{
+    let bin_size = bin_size as usize;
+    let correct:Vec<i32> = scanner.get_corrected().iter(bin_size).step_by(bin_size).collect();
+    let excluded:Vec<i32> = scanner.get_low_mq_window().iter(bin_size).step_by(bin_size).collect();
+
+    let mut hist = Histogram::new(1024);
+    correct.iter().for_each(|v| hist.add(*v as u32));
+
+    return correct.iter().zip(excluded.iter()).map(|(c, e)| hist.normalize((c - e).max(0) as u32)).collect();
+}
+
+/* The standard deviation of the neutral depth, from the median absolute deviation */
+fn estimate_sd(depth: &[f64]) -> f64
+{
+    let fcmp = |a:&f64, b:&f64| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal);
+
+    let mut sorted = depth.to_vec();
+    sorted.sort_unstable_by(fcmp);
+    let median = sorted.get(sorted.len() / 2).cloned().unwrap_or(1.0);
+
+    let mut deviation:Vec<_> = depth.iter().map(|x| (x - median).abs()).collect();
+    deviation.sort_unstable_by(fcmp);
+    let mad = deviation.get(deviation.len() / 2).cloned().unwrap_or(0.0);
+
+    return (mad * 1.4826).max(0.01);
+}
+
+/* The most likely state of each bin. The depth of a state is 0.5 * copy_num, and its variance
+ * grows with the depth like the read counts do */
+fn viterbi(depth: &[f64], copy_nums: &[u32], sd: f64, param: &HmmParam) -> Vec<usize>
+{
+    let n_states = copy_nums.len();
+
+    if depth.is_empty() || n_states == 0 { return Vec::new(); }
+
+    let emission = |state: usize, x: f64| {
+        let mean = 0.5 * copy_nums[state] as f64;
+        let sd = sd * mean.max(0.1).sqrt();
+        -(x - mean) * (x - mean) / (2.0 * sd * sd) - sd.ln()
+    };
+
+    let stay = (1.0 - param.switch_prob).ln();
+    let switch = (param.switch_prob / (n_states.max(2) - 1) as f64).ln();
+
+    let mut score:Vec<f64> = (0..n_states).map(|s| emission(s, depth[0])).collect();
+    let mut back = vec![0u8; depth.len() * n_states];
+
+    for (i, x) in depth.iter().enumerate().skip(1)
+    {
+        let best_prev = (0..n_states).fold(0, |b, s| if score[s] > score[b] { s } else { b });
+
+        score = (0..n_states).map(|s| {
+            let (prev, trans) = if score[s] + stay >= score[best_prev] + switch { (s, score[s] + stay) } else { (best_prev, score[best_prev] + switch) };
+            back[i * n_states + s] = prev as u8;
+            trans + emission(s, *x)
+        }).collect();
+    }
+
+    let mut ret = vec![0; depth.len()];
+    ret[depth.len() - 1] = (0..n_states).fold(0, |b, s| if score[s] > score[b] { s } else { b });
+
+    for i in (1..depth.len()).rev()
+    {
+        ret[i - 1] = back[i * n_states + ret[i]] as usize;
+    }
+
+    return ret;
+}
+
+/* The non-neutral segments of the chromosome. The copy number states are the target copy
+ * numbers and the neutral copy number 2 */
+pub fn segment(scanner: &Scanner, bin_size: u32, copy_nums: &[u32], param: &HmmParam, gc: Option<&GcCorrection>) -> Vec<Segment>
+{
+    let mut states:Vec<u32> = copy_nums.iter().cloned().chain(std::iter::once(2)).collect();
+    states.sort();
+    states.dedup();
+
+    let mut depth = bin_depth(scanner, bin_size);
+
+    if let Some(gc) = gc {
+        depth.iter_mut().enumerate().for_each(|(idx, dep)| *dep /= gc.factor(idx as u32 * bin_size));
+    }
+
+    let sd = estimate_sd(&depth[0..]);
+
+    return make_segments(&viterbi(&depth[0..], &states[0..], sd, param)[0..], &states[0..], bin_size);
+}
+
+fn make_segments(path: &[usize], states: &[u32], bin_size: u32) -> Vec<Segment>
+{
+    let mut ret = Vec::new();
+    let mut begin = 0;
+
+    for i in 1..=path.len()
+    {
+        if i < path.len() && path[i] == path[begin] { continue; }
+
+        if states[path[begin]] != 2
+        {
+            ret.push(Segment {
+                begin: begin as u32 * bin_size,
+                end  : i as u32 * bin_size,
+                copy_num: states[path[begin]],
+            });
+        }
+
+        begin = i;
+    }
+
+    return ret;
+}
+
+#[cfg(test)]
+mod hmm_test {
+    use super::*;
+
+    #[test]
+    fn test_viterbi()
+    {
+        let states = [0, 1, 2, 3];
+        let mut depth = Vec::new();
+
+        /* Neutral, CN1 next to CN0, neutral, CN3, with some noise */
+        for (level, len) in &[(1.0, 50), (0.5, 20), (0.0, 10), (1.0, 50), (1.5, 15), (1.0, 30)]
+        {
+            for i in 0..*len { depth.push(level + if i % 2 == 0 { 0.05 } else { -0.05 }); }
+        }
+
+        let path = viterbi(&depth[0..], &states, estimate_sd(&depth[0..]), &HmmParam::default());
+
+        assert_eq!(make_segments(&path[0..], &states, 100), vec![
+            Segment { begin: 5000, end: 7000, copy_num: 1 },
+            Segment { begin: 7000, end: 8000, copy_num: 0 },
+            Segment { begin: 13000, end: 14500, copy_num: 3 },
+        ]);
+    }
+}
@@ -3,12 +3,23 @@
 pub mod hts;
 pub mod window;
 pub mod bamfile;
+pub mod header;
 pub mod depth_model;
 pub mod scanner;
+pub mod read_filter;
 pub mod histogram;
 pub mod models;
 pub mod frontend;
 pub mod event_pair;
+pub mod dump;
+pub mod container;
+pub mod coverage;
+pub mod hmm;
+pub mod gc;
+pub mod reference;
+
+#[cfg(test)]
+mod test_util;
 
 
 pub fn get_module_path() -> &'static str {
@@ -18,34 +29,58 @@ pub fn get_module_path() -> &'static str {
 pub mod prelude {
     
     use crate::bamfile::BamFile;
-    use crate::frontend::{Frontend,Event};
-    use crate::models::linear::LinearModel;
-    use crate::scanner::Scanner;
+    use crate::frontend::{Frontend,Event,ScoreThreshold};
+    use crate::scanner::{Scanner, MapqConfig};
+    use crate::read_filter::ReadFilter;
     use crate::event_pair::EventPairProc;
     use crate::depth_model::DepthModel;
+    use crate::dump::DumpEncoding;
+    use crate::container::DumpContainer;
+    use crate::hmm::HmmParam;
+    use crate::gc::GcCurve;
     
-    use log::{error, debug};
+    use log::{error, warn, info, debug};
 
     #[derive(Clone)]
     pub struct FrontendParam<'a> {
         pub alignment:&'a str, 
-        pub scanner_dump:&'a str, 
-        pub no_scanner_dump:bool, 
+        pub reference:Option<&'a str>, 
+        pub scanner_dump:Option<&'a DumpContainer>, 
+        pub compress_scanner_dump:bool,
+        /* The number of extra BGZF decompression threads used when scanning the alignment */
+        pub io_threads:u32,
+        pub read_filter:ReadFilter,
+        pub mapq:MapqConfig,
         pub chrom:u32, 
         pub copy_nums:Vec<u32>, 
         pub window_size:u32, 
+        /* The overrides of the model parameter, like "key1=value1,key2=value2" */
+        pub model_param: Option<&'a str>,
+        pub score_threshold: ScoreThreshold<f64>,
+        /* Correct the GC-content bias of the depth with the curve of the sample */
+        pub gc_curve: Option<&'a GcCurve>,
         pub dump_fe: Option<&'a str>, 
-        pub dump_ep: Option<&'a str>
+        pub dump_ep: Option<&'a str>,
+        /* Only scan the range of the chromosome, the scanner dump isn't used in this case */
+        pub range: Option<(u32, u32)>
     }
 
-    fn save_scan_result(scanner:&Scanner, ir_path: &str) -> Result<(), ()>
+    fn save_scan_result(scanner:&Scanner, container: &DumpContainer, compress: bool) -> Result<(), ()>
     {
-        scanner.try_dump(&mut std::fs::File::create(ir_path).unwrap()).unwrap_or_else(|e| {
+        container.append(scanner, if compress { DumpEncoding::Compressed } else { DumpEncoding::Raw }).unwrap_or_else(|e| {
             error!("Unable to produce the scanner dump {:?}", e);
         });
         return Ok(());
     }
 
+    fn load_scan_result(container: &DumpContainer, chrom: &str, size: usize) -> Option<Scanner>
+    {
+        return container.load(chrom, size as u64)
+            .map_err(|e| {
+                warn!("Unable to load {} from the scanner dump {}: {}, rebuilding it from the alignment file", chrom, container.get_path(), e);
+            }).unwrap_or(None);
+    }
+
     fn dump_frontend_events<DM:DepthModel + std::fmt::Debug>(frontend: &Frontend<DM>, fp : &mut std::fs::File)
     {
         use std::io::Write;
@@ -98,46 +133,110 @@ pub mod prelude {
             event_pair
         }
 
+        /* The event pairs made of the non-neutral segments decoded by the HMM, instead of
+         * pairing the events of the depth model */
+        pub fn get_hmm_result<'a>(&'a self, param: &HmmParam) -> Vec<(Event<'a, DM>, Event<'a, DM>)>
+        {
+            let chrom = self.get_chrom_name();
+            let segments = crate::hmm::segment(self.frontend.get_scanner(), self.frontend.get_window_size(), self.frontend.get_copy_nums(), param, self.frontend.get_gc_correction());
+
+            debug!("Chrom {}: {} segments decoded by the HMM", chrom, segments.len());
+
+            let event_pair:Vec<_> = segments.iter().map(|seg| make_event(chrom, seg.begin, seg.end, seg.copy_num)).collect();
+
+            if self.ep_path.is_some()
+            {
+                debug!("Chrom {}: Dumping event pair to file {}", chrom, self.ep_path.as_ref().unwrap());
+                let output = std::fs::File::create(self.ep_path.as_ref().unwrap().as_str());
+                dump_event_pairs(&event_pair, &mut output.unwrap());
+            }
+
+            event_pair
+        }
+
         pub fn get_chrom_name(&self) -> &str {
             return self.frontend.get_scanner().get_chrom();
         }
     }
 
-    pub fn run_linear_frontend<'a>(param: FrontendParam<'a>) -> Result<Context<LinearModel>, ()>
+    pub fn run_frontend<'a, DM:DepthModel<Output = f64>>(param: FrontendParam<'a>) -> Result<Context<DM>, ()>
     {
-        let ir_path = format!("{}.limodump-{}", param.scanner_dump, param.chrom);
-
-        let scanner = if param.no_scanner_dump || !std::path::Path::new(&ir_path[0..]).exists()
+        let scanner = if let Some((begin, end)) = param.range
         {
-            debug!("Scanner dump is not available, load data from the alignment file: {} chromsome: {}", param.alignment, param.chrom);
-            let bam = BamFile::new(param.alignment, param.chrom, None)?;
-            let scanner = Scanner::new(&bam)?;
-            if !param.no_scanner_dump { save_scan_result(&scanner, &ir_path[0..])?; }
-            scanner
+            debug!("Scanning the range {}-{} of the alignment file: {} chromosome: {}", begin, end, param.alignment, param.chrom);
+            let mut bam = BamFile::new(param.alignment, param.chrom, param.reference)?;
+            bam.set_range(begin as usize, end as usize);
+            bam.set_io_threads(param.io_threads)?;
+            Scanner::new(&bam, &param.read_filter, &param.mapq)?
         }
         else
         {
-            debug!("Loading depth information from scanner dump for file: {} chromsome: {}", param.alignment, param.chrom);
-            Scanner::try_load(&mut std::fs::File::open(ir_path).unwrap()).expect("Cannot load")
+            let mut bam = BamFile::new(param.alignment, param.chrom, param.reference)?;
+
+            let loaded = if let Some(container) = param.scanner_dump {
+                debug!("Loading depth information from scanner dump for file: {} chromsome: {}", param.alignment, bam.chrom());
+                load_scan_result(container, bam.chrom(), bam.size())
+            } else { None };
+
+            if let Some(scanner) = loaded { scanner } else {
+                debug!("Scanner dump is not available, load data from the alignment file: {} chromsome: {}", param.alignment, bam.chrom());
+                bam.set_io_threads(param.io_threads)?;
+                Scanner::new(&bam, &param.read_filter, &param.mapq)?
+            }
         };
-    
+
+        return run_frontend_with_scanner(param, scanner);
+    }
+
+    /* Run the frontend on a scanner built by the caller, for example from the single pass
+     * over the alignment file. The scanner is saved to the scanner dump if it's enabled */
+    pub fn run_frontend_with_scanner<'a, DM:DepthModel<Output = f64>>(param: FrontendParam<'a>, scanner: Scanner) -> Result<Context<DM>, ()>
+    {
+        if let (Some(container), None) = (param.scanner_dump, param.range)
+        {
+            if !container.contains(scanner.get_chrom(), scanner.chrom_size() as u64)
+            {
+                save_scan_result(&scanner, container, param.compress_scanner_dump)?;
+            }
+        }
+
+        let range_suffix = param.range.map_or(String::new(), |(begin, end)| format!(":{}-{}", begin, end));
+
+        let dmp = if let Some(text) = param.model_param {
+            let dmp = DM::parse_param(DM::determine_default_param(&scanner, param.window_size, &param.copy_nums[0..]), text)?;
+            debug!("Chrom {}: The model parameter is {:?}", scanner.get_chrom(), dmp);
+            Some(dmp)
+        } else { None };
+
+        let mut frontend = Frontend::<DM>::new(scanner, param.window_size, &param.copy_nums[0..], dmp)?;
+
+        if let Some(curve) = param.gc_curve {
+            let scanner = frontend.get_scanner();
+            let correction = curve.correction(scanner.get_chrom(), scanner.get_offset(), scanner.chrom_size());
+            frontend.set_gc_correction(correction);
+        }
+
+        let threshold = frontend.set_score_threshold(param.score_threshold);
+        info!("Chrom {}: The score threshold of the event pairing is {:.1} ({:?})", frontend.get_scanner().get_chrom(), threshold, param.score_threshold);
+
         let ret = Context{ 
-            frontend: Frontend::<LinearModel>::new(scanner, param.window_size, &param.copy_nums[0..], None)?,
-            fe_path: if let Some(fe) = param.dump_fe { Some(format!("{}-{}", fe, param.chrom)) } else {None},
-            ep_path: if let Some(ep) = param.dump_ep { Some(format!("{}-{}", ep, param.chrom)) } else {None}
+            frontend,
+            fe_path: if let Some(fe) = param.dump_fe { Some(format!("{}-{}{}", fe, param.chrom, range_suffix)) } else {None},
+            ep_path: if let Some(ep) = param.dump_ep { Some(format!("{}-{}{}", ep, param.chrom, range_suffix)) } else {None}
         };
 
         return Ok(ret);
     }
 
-    pub fn make_linear_event<'a>(chrom: &'a str, left: u32, right: u32, copy_num: u32) -> (Event<'a, LinearModel>, Event<'a, LinearModel>)
+    pub fn make_event<'a, DM:DepthModel>(chrom: &'a str, left: u32, right: u32, copy_num: u32) -> (Event<'a, DM>, Event<'a, DM>)
     {
         let left = Event {
             chrom: chrom,
             side : crate::frontend::Side::Left,
-            score: 0.0,
+            score: Default::default(),
             pos  : left,
             copy_num: copy_num,
+            level: None,
             total_dep: 0,
             lowmq_dep: 0
         };
@@ -145,9 +244,10 @@ pub mod prelude {
         let right = Event {
             chrom: chrom,
             side : crate::frontend::Side::Right,
-            score: 0.0,
+            score: Default::default(),
             pos  : right,
             copy_num: copy_num,
+            level: None,
             total_dep: 0,
             lowmq_dep: 0
         };
@@ -1,6 +1,16 @@
 use crate::depth_model::DepthModel;
 use crate::scanner::Scanner;
 
+use std::str::FromStr;
+
+use log::error;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinearParam {
+    /* The length of the ramp, which is the read length plus the window size by default */
+    pub len: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct LinearModel {
     k : f64,
@@ -42,11 +52,23 @@ fn square<T:std::ops::Mul + Copy>(what:T) -> T::Output
 impl DepthModel for LinearModel {
     type Input = f64;
     type Output = f64;
-    type ParamType = u32;
+    type ParamType = LinearParam;
 
-    fn determine_default_param(scanner:&Scanner, winsize: u32, _target:&[u32]) -> u32
+    fn determine_default_param(scanner:&Scanner, winsize: u32, _target:&[u32]) -> LinearParam
     {
-        scanner.get_common_read_length() + winsize
+        LinearParam { len: scanner.get_common_read_length() + winsize }
+    }
+
+    fn set_param(param: &mut LinearParam, key: &str, value: &str) -> Result<(), ()>
+    {
+        match key {
+            "len" => param.len = u32::from_str(value).map_err(|_| { error!("Invalid model parameter len={}", value); })?,
+            _ => {
+                error!("Unknown parameter {} of the linear model", key);
+                return Err(());
+            }
+        }
+        return Ok(());
     }
 
     fn score_cmp(left : Self::Output, right : Self::Output) -> i32
@@ -55,22 +77,25 @@ impl DepthModel for LinearModel {
         else if left < right { -1 }
         else { 1 };
     }
-    fn score_threshold(win_size:u32, score : Self::Output) -> bool
+    fn default_score_threshold(win_size:u32) -> Self::Output
+    {
+        return (7000.0 / 550.0) * ((win_size + 250) as f64);
+    }
+    fn score_threshold(threshold : Self::Output, score : Self::Output) -> bool
     {
-        /* TODO: how to determine the threshold dynamically? */
-        return score < (7000.0 / 550.0) * ((win_size + 250) as f64);
+        return score < threshold;
     }
 
-    fn create_model(copy_num:u32, left: bool, p:u32) -> Self
+    fn create_model(copy_num:u32, left: bool, p:LinearParam) -> Self
     {
         let target_depth = 0.5 * (copy_num as f64);
 
         if left
         {
-            return LinearModel::new(p, 1.0, target_depth);
+            return LinearModel::new(p.len, 1.0, target_depth);
         }
 
-        return LinearModel::new(p, target_depth, 1.0);
+        return LinearModel::new(p.len, target_depth, 1.0);
     }
     fn put(&mut self, next: Self::Input) 
     {
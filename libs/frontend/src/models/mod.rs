@@ -1 +1,60 @@
 pub mod linear;
+pub mod step;
+pub mod negbin;
+
+use crate::depth_model::DepthModel;
+use self::linear::LinearModel;
+use self::step::StepModel;
+use self::negbin::NegBinModel;
+
+/* The depth models selectable at runtime. A new model is added here and to the registry
+ * below, the code using the models is generic over them */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModelKind {
+    /* The ramp to the depth of each target copy number */
+    Linear,
+    /* The step to a free level, the copy number is estimated */
+    Step,
+    /* The log-likelihood ratio of the read count under the negative binomial distribution */
+    NegBin,
+}
+
+/* (kind, name, description) of each model */
+const REGISTRY: &[(ModelKind, &str, &str)] = &[
+    (ModelKind::Linear, "linear", "The ramp to the depth of each target copy number"),
+    (ModelKind::Step, "step", "The step to a free level, which estimates the copy number"),
+    (ModelKind::NegBin, "negbin", "The log-likelihood ratio of the read count under the negative binomial distribution"),
+];
+
+/* The routine generic over the depth model, which is run with the model selected at runtime */
+pub trait ModelVisitor {
+    type Output;
+    fn visit<DM:DepthModel<Output = f64> + std::fmt::Debug>(self) -> Self::Output;
+}
+
+impl ModelKind {
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        return REGISTRY.iter().find(|(_, n, _)| *n == name).map(|(kind, _, _)| *kind);
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        return REGISTRY.iter().find(|(kind, _, _)| kind == self).map_or("", |(_, name, _)| name);
+    }
+
+    /* The (name, description) of all the models */
+    pub fn list() -> Vec<(&'static str, &'static str)>
+    {
+        return REGISTRY.iter().map(|(_, name, desc)| (*name, *desc)).collect();
+    }
+
+    pub fn dispatch<V:ModelVisitor>(&self, visitor: V) -> V::Output
+    {
+        return match self {
+            ModelKind::Linear => visitor.visit::<LinearModel>(),
+            ModelKind::Step => visitor.visit::<StepModel>(),
+            ModelKind::NegBin => visitor.visit::<NegBinModel>(),
+        };
+    }
+}
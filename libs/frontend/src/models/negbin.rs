@@ -0,0 +1,212 @@
+use crate::depth_model::DepthModel;
+use crate::histogram::Histogram;
+use crate::scanner::Scanner;
+
+use std::str::FromStr;
+
+use log::error;
+
+/* The expected depth of the copy number 0 relative to the neutral depth, since there are
+ * always some mismapped reads */
+const MIN_DEPTH_RATIO: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy)]
+pub struct NegBinParam {
+    /* The length of the model, which is the read length plus the window size by default */
+    pub len: u32,
+    /* The window size, the depth of the positions closer than this are counted from mostly the
+     * same reads, so the log-likelihood is divided by it */
+    pub stride: u32,
+    /* The neutral read count of a window */
+    pub mean: f64,
+    /* The dispersion r of the read count, the variance is mean + mean^2 / r. Infinite for the
+     * Poisson distribution */
+    pub dispersion: f64,
+}
+
+/* The model scoring the read counts under the negative binomial distribution. The first half
+ * of the model is at the neutral depth and the second half is at the depth of the copy number
+ * for the left side, and the other way around for the right side. The score is the negated
+ * log-likelihood ratio of this step against the better one of the flat neutral depth and the
+ * flat depth of the copy number, so a score of -5 means the step is e^5 times more likely */
+#[derive(Debug, Clone)]
+pub struct NegBinModel {
+    left : bool,
+    buf  : Vec<f64>,
+    /* The sum of the read count in each half */
+    first : f64,
+    second: f64,
+    /* The log-likelihood ratio of a window of count k against the neutral depth is a + b * k */
+    a    : f64,
+    b    : f64,
+    mean : f64,
+    stride: f64,
+    idx  : u32,
+    count: u32,
+    len  : u32
+}
+
+impl NegBinModel {
+    pub fn new(param: NegBinParam, copy_num: u32, left: bool) -> Self
+    {
+        let mu0 = param.mean.max(1e-5);
+        let mu1 = (0.5 * copy_num as f64).max(MIN_DEPTH_RATIO) * mu0;
+        let r = param.dispersion;
+
+        let (a, b) = if r.is_finite() {
+            let ratio = ((r + mu0) / (r + mu1)).ln();
+            (r * ratio, (mu1 / mu0).ln() + ratio)
+        } else {
+            (mu0 - mu1, (mu1 / mu0).ln())
+        };
+
+        return NegBinModel {
+            left,
+            buf: vec![0f64; param.len as usize],
+            first: 0f64,
+            second: 0f64,
+            a,
+            b,
+            mean: param.mean,
+            stride: param.stride.max(1) as f64,
+            idx: 0,
+            count: 0,
+            len: param.len
+        };
+    }
+
+    /* The log-likelihood ratio of the first and second half against the neutral depth */
+    fn half_llr(&self) -> (f64, f64)
+    {
+        let half = self.len / 2;
+        let first = (half as f64 * self.a + self.b * self.first) / self.stride;
+        let second = ((self.len - half) as f64 * self.a + self.b * self.second) / self.stride;
+        return (first, second);
+    }
+}
+
+impl DepthModel for NegBinModel {
+    type Input = f64;
+    type Output = f64;
+    type ParamType = NegBinParam;
+
+    /* The dispersion is estimated from the read count of the windows with any read */
+    fn determine_default_param(scanner:&Scanner, winsize: u32, _target:&[u32]) -> NegBinParam
+    {
+        let mut hist = Histogram::new(1024);
+        scanner.get_corrected().iter(winsize as usize).for_each(|v:i32| hist.add(v as u32));
+
+        /* Both the mean and the dispersion leave out the empty windows of the gaps */
+        let (mean, var) = hist.get_moments(1);
+
+        NegBinParam {
+            len: scanner.get_common_read_length() + winsize,
+            stride: winsize,
+            mean,
+            dispersion: if var > mean { mean * mean / (var - mean) } else { std::f64::INFINITY },
+        }
+    }
+
+    fn set_param(param: &mut NegBinParam, key: &str, value: &str) -> Result<(), ()>
+    {
+        let invalid = || { error!("Invalid model parameter {}={}", key, value); };
+        match key {
+            "len" => param.len = u32::from_str(value).map_err(|_| invalid())?,
+            "dispersion" => param.dispersion = f64::from_str(value).map_err(|_| invalid())?,
+            _ => {
+                error!("Unknown parameter {} of the negative binomial model", key);
+                return Err(());
+            }
+        }
+        return Ok(());
+    }
+
+    fn score_cmp(left : Self::Output, right : Self::Output) -> i32
+    {
+        return if (left - right).abs() < 1e-5 { 0 }
+        else if left < right { -1 }
+        else { 1 };
+    }
+
+    /* The step should be e^5 times more likely than no change */
+    fn default_score_threshold(_win_size:u32) -> Self::Output
+    {
+        return -5.0;
+    }
+
+    fn score_threshold(threshold : Self::Output, score : Self::Output) -> bool
+    {
+        return score < threshold;
+    }
+
+    fn create_model(copy_num:u32, left: bool, p:NegBinParam) -> Self
+    {
+        return NegBinModel::new(p, copy_num, left);
+    }
+
+    /* The input is the normalized depth, which is turned back into the read count */
+    fn put(&mut self, next: Self::Input)
+    {
+        let next = next * self.mean;
+        let half = self.len / 2;
+
+        if self.count >= self.len
+        {
+            let oldest = self.buf[self.idx as usize];
+            let moving = self.buf[((self.idx + half) % self.len) as usize];
+            self.first += moving - oldest;
+            self.second += next - moving;
+            self.buf[self.idx as usize] = next;
+            self.idx = if self.idx + 1 == self.len { 0 } else { self.idx + 1 };
+        }
+        else
+        {
+            if self.count < half { self.first += next; } else { self.second += next; }
+            self.buf[self.count as usize] = next;
+            self.count += 1;
+        }
+    }
+
+    fn get_score(&self) -> Self::Output
+    {
+        if self.count < self.len { return 0.0; }
+
+        let (first, second) = self.half_llr();
+
+        let llr = if self.left { second.min(-first) } else { first.min(-second) };
+
+        return -llr;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negbin_model()
+    {
+        let param = NegBinParam { len: 10, stride: 5, mean: 100.0, dispersion: std::f64::INFINITY };
+
+        let mut left = NegBinModel::new(param, 1, true);
+        let mut right = NegBinModel::new(param, 1, false);
+
+        (0..10).for_each(|_| { left.put(1.0); right.put(1.0); });
+
+        /* No evidence of the change in the neutral region */
+        assert!(left.get_score() > 0.0);
+
+        (0..5).for_each(|_| { left.put(0.5); right.put(0.5); });
+
+        /* Each half is one independent window, ln(L(k=50|50) / L(k=50|100)) = 50 - 50 * ln(2) */
+        let expected = 50.0 - 50.0 * 2f64.ln();
+        assert!((left.get_score() + expected).abs() < 1e-6);
+        assert!(right.get_score() > 0.0);
+
+        /* Overdispersion makes the same change less likely */
+        let mut dispersed = NegBinModel::new(NegBinParam { dispersion: 10.0, ..param }, 1, true);
+        (0..5).for_each(|_| dispersed.put(1.0));
+        (0..5).for_each(|_| dispersed.put(0.5));
+        assert!(dispersed.get_score() < 0.0 && dispersed.get_score() > left.get_score());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::depth_model::DepthModel;
+use crate::scanner::Scanner;
+
+use std::str::FromStr;
+
+use log::error;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StepParam {
+    /* The length of the ramp, which is the read length plus the window size by default */
+    pub len: u32,
+    /* The smallest change of the level taken as an event, half a copy by default */
+    pub min_change: f64,
+}
+
+/* The two-level step model. The depth changes from the neutral level 1.0 to a free level, which
+ * is estimated from the data instead of being assumed from the copy number. Since the input is
+ * the depth of a sliding window, the step shows up as a ramp over the model length, so the
+ * model fits the ramp from 1.0 to the free level with the least squares.
+ *
+ * The score is the negated reduction of the squared error compared with the flat neutral
+ * level, so a lower score is a stronger change, the same as the other models. The copy number
+ * only decides the direction of the change */
+#[derive(Debug, Clone)]
+pub struct StepModel {
+    /* The change goes from 1.0 to the level for the left side, and the other way around for the right side */
+    left: bool,
+    gain: bool,
+    buf : Vec<f64>,
+    /* The sum of the depth minus 1.0 */
+    sum : f64,
+    /* The sum of the depth minus 1.0 weighted by the age, the oldest one weights 0 */
+    moment: f64,
+    /* The sum of the square of the weights */
+    weight_square: f64,
+    min_change: f64,
+    idx : u32,
+    count: u32,
+    len : u32
+}
+
+impl StepModel {
+    pub fn new(param:StepParam, left:bool, gain:bool) -> StepModel
+    {
+        let len = param.len;
+        let n = len as f64;
+        return StepModel {
+            left,
+            gain,
+            buf: vec![0f64;len as usize],
+            sum: 0f64,
+            moment: 0f64,
+            weight_square: ((n - 1.0) * n * (2.0 * n - 1.0) / 6.0).max(1.0),
+            min_change: param.min_change,
+            idx: 0,
+            count: 0,
+            len
+        };
+    }
+
+    /* The sum of the depth minus 1.0 weighted by the ramp, which is 0 at the neutral end */
+    fn weighted_sum(&self) -> f64
+    {
+        if self.left { self.moment } else { (self.len - 1) as f64 * self.sum - self.moment }
+    }
+
+    /* The level after the change relative to the neutral level, None before the model is full
+     * or if the change goes in the other direction or it's too small */
+    pub fn get_level(&self) -> Option<f64>
+    {
+        if self.count < self.len { return None; }
+
+        let delta = self.weighted_sum() * ((self.len - 1) as f64) / self.weight_square;
+
+        if (delta > 0.0) != self.gain || delta == 0.0 || delta.abs() < self.min_change { return None; }
+
+        return Some((1.0 + delta).max(0.0));
+    }
+}
+
+impl DepthModel for StepModel {
+    type Input = f64;
+    type Output = f64;
+    type ParamType = StepParam;
+
+    fn determine_default_param(scanner:&Scanner, winsize: u32, _target:&[u32]) -> StepParam
+    {
+        StepParam { len: scanner.get_common_read_length() + winsize, min_change: 0.25 }
+    }
+
+    fn set_param(param: &mut StepParam, key: &str, value: &str) -> Result<(), ()>
+    {
+        let invalid = || { error!("Invalid model parameter {}={}", key, value); };
+        match key {
+            "len" => param.len = u32::from_str(value).map_err(|_| invalid())?,
+            "min_change" => param.min_change = f64::from_str(value).map_err(|_| invalid())?,
+            _ => {
+                error!("Unknown parameter {} of the step model", key);
+                return Err(());
+            }
+        }
+        return Ok(());
+    }
+
+    /* A single model for each direction, since the level isn't assumed */
+    fn model_copy_nums(copy_nums: &[u32]) -> Vec<u32>
+    {
+        let mut ret = Vec::new();
+        if copy_nums.iter().any(|cn| *cn < 2) { ret.push(1); }
+        if copy_nums.iter().any(|cn| *cn > 2) { ret.push(3); }
+        return ret;
+    }
+
+    fn score_cmp(left : Self::Output, right : Self::Output) -> i32
+    {
+        return if (left - right).abs() < 1e-5 { 0 }
+        else if left < right { -1 }
+        else { 1 };
+    }
+
+    /* Half of the squared error reduced by a heterozygous deletion */
+    fn default_score_threshold(win_size:u32) -> Self::Output
+    {
+        return -((win_size + 250) as f64) / 24.0;
+    }
+
+    fn score_threshold(threshold : Self::Output, score : Self::Output) -> bool
+    {
+        return score < threshold;
+    }
+
+    fn create_model(copy_num:u32, left: bool, p:StepParam) -> Self
+    {
+        return StepModel::new(p, left, copy_num > 2);
+    }
+
+    fn put(&mut self, next: Self::Input)
+    {
+        let next = next - 1.0;
+
+        if self.count >= self.len
+        {
+            let oldest = self.buf[self.idx as usize];
+            self.moment += (self.len - 1) as f64 * next - (self.sum - oldest);
+            self.sum += next - oldest;
+            self.buf[self.idx as usize] = next;
+            self.idx = if self.idx + 1 == self.len { 0 } else { self.idx + 1 };
+        }
+        else
+        {
+            self.moment += self.count as f64 * next;
+            self.sum += next;
+            self.buf[self.count as usize] = next;
+            self.count += 1;
+        }
+    }
+
+    fn get_score(&self) -> Self::Output
+    {
+        if self.get_level().is_none() { return 0.0; }
+
+        let weighted = self.weighted_sum();
+
+        return -(weighted * weighted) / self.weight_square;
+    }
+
+    fn estimated_copy_num(&self) -> Option<u32>
+    {
+        let level = self.get_level()?;
+        let copy_num = (level * 2.0).round() as u32;
+
+        return Some(if self.gain { copy_num.max(3) } else { copy_num.min(1) });
+    }
+
+    fn estimated_level(&self) -> Option<f64> { self.get_level() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_step_model()
+    {
+        let len = 11;
+        let param = StepParam { len, min_change: 0.25 };
+        let ramp = |from:f64, to:f64| (0..len).map(move |i| from + (to - from) * (i as f64) / ((len - 1) as f64));
+
+        let mut left = StepModel::new(param, true, false);
+        let mut gain = StepModel::new(param, true, true);
+
+        (0..5).for_each(|_| { left.put(1.0); gain.put(1.0); });
+        ramp(1.0, 0.5).for_each(|x| { left.put(x); gain.put(x); });
+
+        assert!((left.get_level().unwrap() - 0.5).abs() < 1e-6);
+        assert_eq!(left.estimated_copy_num(), Some(1));
+        assert!((left.get_score() + 0.9625).abs() < 1e-6);
+        assert_eq!(gain.get_level(), None);
+        assert_eq!(gain.get_score(), 0.0);
+
+        let mut right = StepModel::new(param, false, true);
+        ramp(2.0, 1.0).for_each(|x| right.put(x));
+
+        assert!((right.get_level().unwrap() - 2.0).abs() < 1e-6);
+        assert_eq!(right.estimated_copy_num(), Some(4));
+
+        let mut neutral = StepModel::new(param, true, false);
+        (0..20).for_each(|_| neutral.put(1.0));
+        assert_eq!(neutral.get_score(), 0.0);
+
+        let mut small = StepModel::new(param, true, false);
+        ramp(1.0, 0.9).for_each(|x| small.put(x));
+        assert_eq!(small.get_level(), None);
+
+        let param = StepModel::parse_param(param, "len=21,min_change=0.1").unwrap();
+        assert_eq!((param.len, param.min_change), (21, 0.1));
+        assert!(StepModel::parse_param(param, "width=3").is_err());
+    }
+}
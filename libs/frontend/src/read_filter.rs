@@ -0,0 +1,103 @@
+use crate::scanner::AlignmentType;
+
+pub const FLAG_PAIRED: u16 = 0x1;
+pub const FLAG_PROPER_PAIR: u16 = 0x2;
+pub const FLAG_SECONDARY: u16 = 0x100;
+pub const FLAG_QC_FAIL: u16 = 0x200;
+pub const FLAG_DUPLICATE: u16 = 0x400;
+pub const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/* Decides which reads are counted, it's shared by the scanner and the probability validation.
+ * A read is counted only if all the include flags are set, none of the exclude flags is set
+ * and the mapping quality isn't lower than min_mapq. Note that the low MAPQ depth and the
+ * MAPQ tiers are made of the reads below the cutoffs of MapqConfig, so they only see the
+ * reads from min_mapq up */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadFilter {
+    pub include_flags: u16,
+    pub exclude_flags: u16,
+    pub min_mapq     : u32,
+    pub proper_pair  : bool,
+}
+
+impl Default for ReadFilter {
+    /* Secondary, QC-fail and duplicate reads are excluded by default */
+    fn default() -> Self
+    {
+        return ReadFilter {
+            include_flags: 0,
+            exclude_flags: FLAG_SECONDARY | FLAG_QC_FAIL | FLAG_DUPLICATE,
+            min_mapq     : 0,
+            proper_pair  : false,
+        };
+    }
+}
+
+impl ReadFilter {
+    /* Parse a flag mask, either in decimal or in hex with the 0x prefix, like samtools does */
+    pub fn parse_flags(text: &str) -> Result<u16, ()>
+    {
+        let text = text.trim();
+        if text.starts_with("0x") || text.starts_with("0X") {
+            return u16::from_str_radix(&text[2..], 16).map_err(|_| ());
+        }
+        return text.parse::<u16>().map_err(|_| ());
+    }
+
+    pub fn accept<T:AlignmentType>(&self, read: &T) -> bool
+    {
+        let flags = read.get_flags();
+
+        let include = self.include_flags | if self.proper_pair { FLAG_PAIRED | FLAG_PROPER_PAIR } else { 0 };
+
+        return flags & include == include
+            && flags & self.exclude_flags == 0
+            && read.get_mqual() >= self.min_mapq;
+    }
+
+    /* The description recorded in the scanner dump */
+    pub fn describe(&self) -> String
+    {
+        return format!("include=0x{:x},exclude=0x{:x},min_mapq={},proper_pair={}",
+                       self.include_flags, self.exclude_flags, self.min_mapq, self.proper_pair);
+    }
+}
+
+#[cfg(test)]
+mod read_filter_test {
+    use super::*;
+
+    struct TestRead {
+        flags: u16,
+        qual : u32
+    }
+
+    impl AlignmentType for TestRead {
+        fn get_begin(&self) -> u32 { 0 }
+        fn get_end(&self) -> u32 { 100 }
+        fn get_length(&self) -> u32 { 100 }
+        fn get_qpos(&self) -> (u32, u32) { (0, 100) }
+        fn check_is_split_read(&self) -> bool { false }
+        fn get_mqual(&self) -> u32 { self.qual }
+        fn get_flags(&self) -> u16 { self.flags }
+    }
+
+    #[test]
+    fn test_read_filter()
+    {
+        let filter = ReadFilter::default();
+        assert!(filter.accept(&TestRead { flags: 0x63, qual: 0 }));
+        assert!(!filter.accept(&TestRead { flags: 0x463, qual: 60 }));
+        assert!(!filter.accept(&TestRead { flags: 0x100, qual: 60 }));
+        assert!(filter.accept(&TestRead { flags: 0x800, qual: 60 }));
+
+        let filter = ReadFilter { min_mapq: 20, proper_pair: true, ..ReadFilter::default() };
+        assert!(!filter.accept(&TestRead { flags: 0x1, qual: 60 }));
+        assert!(!filter.accept(&TestRead { flags: 0x3, qual: 10 }));
+        assert!(filter.accept(&TestRead { flags: 0x3, qual: 20 }));
+
+        assert_eq!(ReadFilter::parse_flags("0x704"), Ok(0x704));
+        assert_eq!(ReadFilter::parse_flags("1796"), Ok(0x704));
+        assert!(ReadFilter::parse_flags("0xzz").is_err());
+    }
+}
@@ -0,0 +1,72 @@
+/* The reader of the indexed reference FASTA, through the faidx API of htslib */
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr::null_mut;
+
+use log::error;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct faidx_t { _private: [u8; 0] }
+
+extern "C" {
+    fn fai_load(path: *const c_char) -> *mut faidx_t;
+    fn fai_destroy(fai: *mut faidx_t);
+    fn faidx_fetch_seq(fai: *const faidx_t, name: *const c_char, begin: c_int, end: c_int, len: *mut c_int) -> *mut c_char;
+    fn free(ptr: *mut c_void);
+}
+
+pub struct Reference {
+    fai: *mut faidx_t,
+}
+
+impl Drop for Reference {
+    fn drop(&mut self)
+    {
+        if self.fai != null_mut()
+        {
+            unsafe { fai_destroy(self.fai) };
+            self.fai = null_mut();
+        }
+    }
+}
+
+impl Reference {
+    pub fn open(path: &str) -> Result<Self, ()>
+    {
+        let fai = unsafe { fai_load(CString::new(path).unwrap().as_ptr()) };
+
+        if fai == null_mut()
+        {
+            error!("Cannot load the index of the reference file {}", path);
+            return Err(());
+        }
+
+        return Ok(Reference { fai });
+    }
+
+    /* The bases of [begin, end), which may be shorter at the end of the contig */
+    pub fn fetch(&self, chrom: &str, begin: u32, end: u32) -> Result<Vec<u8>, ()>
+    {
+        let mut len = 0;
+        let seq = unsafe { faidx_fetch_seq(self.fai, CString::new(chrom).unwrap().as_ptr(), begin as c_int, end as c_int - 1, &mut len) };
+
+        if seq == null_mut() || len < 0
+        {
+            error!("Cannot fetch {}:{}-{} from the reference file", chrom, begin, end);
+            return Err(());
+        }
+
+        let ret = unsafe { std::slice::from_raw_parts(seq as *const u8, len as usize) }.to_vec();
+
+        unsafe { free(seq as *mut c_void) };
+
+        return Ok(ret);
+    }
+
+    /* The upper case base at the 0-based position */
+    pub fn fetch_base(&self, chrom: &str, pos: u32) -> Result<u8, ()>
+    {
+        return self.fetch(chrom, pos, pos + 1)?.first().map(|base| base.to_ascii_uppercase()).ok_or(());
+    }
+}
@@ -1,7 +1,9 @@
 use super::window::Window;
-use super::bamfile::{BamFile, Alignment, BamFileIter};
+use super::bamfile::{BamFile, Alignment, BamFileIter, ContigReads};
+use super::read_filter::ReadFilter;
+use super::coverage::CoverageReader;
+use super::dump::{DumpWriter, DumpReader, DumpEncoding, MAX_NAME_LEN, invalid_data};
 use std::io::{Write, Read};
-use std::slice;
 
 pub trait AlignmentType {
     fn get_begin(&self) -> u32;
@@ -10,6 +12,7 @@ pub trait AlignmentType {
     fn get_qpos(&self) -> (u32, u32);
     fn check_is_split_read(&self) -> bool;
     fn get_mqual(&self) -> u32;
+    fn get_flags(&self) -> u16;
 }
 
 impl <'a> AlignmentType for Alignment<'a> {
@@ -19,6 +22,7 @@ impl <'a> AlignmentType for Alignment<'a> {
     fn check_is_split_read(&self) -> bool {self.is_split_read() }
     fn get_mqual(&self) -> u32 {self.mqual() }
     fn get_qpos(&self) -> (u32, u32) { (self.begin(), self.end()) }
+    fn get_flags(&self) -> u16 { Alignment::get_flags(self) }
 }
 
 pub trait Input<'a, T:AlignmentType> {
@@ -26,23 +30,80 @@ pub trait Input<'a, T:AlignmentType> {
     fn size(&self) -> usize;
     fn try_iter(&'a self) -> Result<Self::IterType, ()>;
     fn get_chrom(&self) -> &str;
+    /* The genome position of the first base of the input */
+    fn get_offset(&self) -> usize { 0 }
 }
 
 impl <'a> Input<'a, Alignment<'a>> for BamFile {
     type IterType = BamFileIter<'a>;
-    fn size(&self) -> usize { self.size() }
-    fn try_iter(&'a self) -> Result<Self::IterType, ()> { self.try_iter() }
+    fn size(&self) -> usize { self.get_range().1 - self.get_range().0 }
+    fn try_iter(&'a self) -> Result<Self::IterType, ()> { self.try_iter_range(self.get_range().0, self.get_range().1) }
     fn get_chrom(&self) -> &str { self.chrom() }
+    fn get_offset(&self) -> usize { self.get_range().0 }
+}
+
+/* How the reads are classified by the mapping quality. A read with the MAPQ lower than
+ * low_mapq goes to the low MAPQ depth instead of the raw depth. Each tier is tracked as a
+ * separate window counting the reads with the MAPQ lower than the tier cutoff */
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapqConfig {
+    pub low_mapq: u32,
+    pub tiers   : Vec<u32>,
+}
+
+impl Default for MapqConfig {
+    fn default() -> Self
+    {
+        return MapqConfig { low_mapq: 1, tiers: Vec::new() };
+    }
+}
+
+impl MapqConfig {
+    pub fn describe(&self) -> String
+    {
+        let tiers:Vec<_> = self.tiers.iter().map(|x| x.to_string()).collect();
+        return format!("low_mapq={},tiers={}", self.low_mapq, tiers.join(":"));
+    }
 }
 
 pub struct Scanner {
     corrected_window : Window<i32>,
     low_mq_window    : Window<i32>,
     raw_window       : Window<i32>,
+    /* (cutoff, depth of the non-split reads with the MAPQ lower than cutoff) */
+    mapq_tiers       : Vec<(u32, Window<i32>)>,
     common_read_len     : u32,
     common_read_len_cnt : u32,
     chrom               : Box<str>,
     size                : u32,
+    offset              : u32,
+}
+
+/* Turns the per-base depth into the reads. A read starts each time read_len units of the
+ * depth are accumulated and it's centered at the position, so the read depth of each base
+ * follows the per-base depth */
+struct PseudoReads {
+    read_len: usize,
+    carry   : usize,
+}
+
+impl PseudoReads {
+    fn new(read_len: usize) -> Self
+    {
+        return PseudoReads { read_len: read_len.max(1), carry: 0 };
+    }
+
+    fn put<F:FnMut(usize, usize)>(&mut self, pos: usize, depth: usize, mut emit: F)
+    {
+        self.carry += depth;
+
+        while self.carry >= self.read_len
+        {
+            self.carry -= self.read_len;
+            let begin = pos.saturating_sub(self.read_len / 2);
+            emit(begin, begin + self.read_len);
+        }
+    }
 }
 
 impl Scanner {
@@ -67,109 +128,263 @@ impl Scanner {
         &self.raw_window
     }
 
+    pub fn get_mapq_tiers(&self) -> &[(u32, Window<i32>)]
+    {
+        &self.mapq_tiers[0..]
+    }
+
     pub fn get_chrom(&self) -> &str 
     {
         return self.chrom.as_ref();
     }
 
-    #[allow(dead_code)]
-    pub fn try_dump<T:Write>(&self, fp:&mut T) -> Result<(), std::io::Error>
+    /* All the positions in the scanner are relative to this genome position */
+    pub fn get_offset(&self) -> u32
     {
-        let header = [self.chrom.len() as u32 ,self.common_read_len, self.common_read_len_cnt];
-        let header_ptr = &header as *const u32;
+        self.offset
+    }
 
-        fp.write_all(unsafe{ slice::from_raw_parts(header_ptr as *const u8, std::mem::size_of::<u32>() * header.len())})?;
-        fp.write_all(unsafe{ std::mem::transmute(&self.chrom[0..])})?;
-        self.corrected_window.try_dump(fp)?;
-        self.low_mq_window.try_dump(fp)?;
-        self.raw_window.try_dump(fp)?;
+    #[allow(dead_code)]
+    pub fn try_dump<T:Write>(&self, fp:&mut T, encoding: DumpEncoding) -> Result<(), std::io::Error>
+    {
+        let mut writer = DumpWriter::new(fp, encoding)?;
+
+        writer.write_u64((self.chrom.len() + std::mem::size_of::<u32>() * 5) as u64)?;
+        writer.write_u32(self.chrom.len() as u32)?;
+        writer.write_bytes(self.chrom.as_bytes())?;
+        writer.write_u32(self.common_read_len)?;
+        writer.write_u32(self.common_read_len_cnt)?;
+        writer.write_u32(self.size)?;
+        writer.write_u32(self.offset)?;
+
+        writer.write_u32(3 + self.mapq_tiers.len() as u32)?;
+        self.corrected_window.try_dump(&mut writer)?;
+        self.low_mq_window.try_dump(&mut writer)?;
+        self.raw_window.try_dump(&mut writer)?;
+
+        for (cutoff, window) in self.mapq_tiers.iter()
+        {
+            writer.write_u32(*cutoff)?;
+            window.try_dump(&mut writer)?;
+        }
 
-        return Ok(());
+        return writer.finish();
     }
 
     pub fn try_load<T:Read>(fp:&mut T) -> Result<Scanner, std::io::Error>
     {
-        let mut header = [0u32;3];
-        let header_ptr = header.as_mut_ptr();
+        let mut reader = DumpReader::new(fp)?;
+
+        let header_size = reader.read_u64()? as usize;
+        let name_size = reader.read_u32()? as usize;
+
+        if name_size > MAX_NAME_LEN || header_size != name_size + std::mem::size_of::<u32>() * 5
+        {
+            return Err(invalid_data(format!("malformed header section of {} bytes", header_size)));
+        }
 
-        fp.read(unsafe{ slice::from_raw_parts_mut(header_ptr as *mut u8, std::mem::size_of::<u32>() * header.len()) })?;
+        let mut name_buf = vec![0u8; name_size];
+        reader.read_bytes(&mut name_buf[0..])?;
 
-        let mut name_buf = vec![0u8; header[0] as usize];
+        let name = String::from_utf8(name_buf).map_err(|_| invalid_data("invalid chromosome name".to_string()))?;
+        let common_read_len = reader.read_u32()?;
+        let common_read_len_cnt = reader.read_u32()?;
+        let size = reader.read_u32()?;
+        let offset = reader.read_u32()?;
 
-        fp.read(&mut name_buf[0..])?;
+        let num_windows = reader.read_u32()?;
 
-        let name = std::str::from_utf8(&name_buf[0..]).unwrap();
-        let corrected_window = Window::<i32>::try_load(fp)?;
-        let low_mq_window = Window::<i32>::try_load(fp)?;
-        let raw_window = Window::<i32>::try_load(fp)?;
-        let size = corrected_window.size() as u32;
+        if num_windows < 3
+        {
+            return Err(invalid_data(format!("unexpected number of windows {}", num_windows)));
+        }
+
+        let corrected_window = Window::<i32>::try_load(&mut reader, size as usize)?;
+        let low_mq_window = Window::<i32>::try_load(&mut reader, size as usize)?;
+        let raw_window = Window::<i32>::try_load(&mut reader, size as usize)?;
+
+        let mut mapq_tiers = Vec::new();
+
+        for _ in 3..num_windows
+        {
+            let cutoff = reader.read_u32()?;
+            mapq_tiers.push((cutoff, Window::<i32>::try_load(&mut reader, size as usize)?));
+        }
+
+        reader.finish()?;
 
         return Ok(Scanner {
-            common_read_len: header[1],
-            common_read_len_cnt: header[2],
-            chrom : String::from(name).into_boxed_str(),
+            common_read_len,
+            common_read_len_cnt,
+            chrom : name.into_boxed_str(),
             corrected_window,
             low_mq_window,
             raw_window,
+            mapq_tiers,
             size,
+            offset,
         });
     }
 
-    pub fn new<'a, IType, AType>(bam:&'a IType) -> Result<Scanner, ()>
+    fn empty(chrom: &str, size: usize, offset: usize, mapq: &MapqConfig) -> Scanner
+    {
+        return Scanner {
+            corrected_window : Window::<i32>::new(size),
+            low_mq_window    : Window::<i32>::new(size),
+            raw_window       : Window::<i32>::new(size),
+            mapq_tiers       : mapq.tiers.iter().map(|cutoff| (*cutoff, Window::<i32>::new(size))).collect(),
+            common_read_len  : 0,
+            common_read_len_cnt: 0,
+            chrom            : String::from(chrom).into_boxed_str(),
+            size: size as u32,
+            offset: offset as u32,
+        };
+    }
+
+    /* Count a read accepted by the read filter */
+    fn add_read<AType:AlignmentType>(&mut self, read: &AType, mapq: &MapqConfig)
+    {
+        if self.common_read_len != read.get_length() 
+        {
+            if self.common_read_len_cnt > 0 
+            {
+                self.common_read_len_cnt -= 1
+            }
+            else
+            {
+                self.common_read_len_cnt += 1;
+            }
+
+            if self.common_read_len_cnt == 0
+            {
+                self.common_read_len = read.get_length();
+            }
+        } else { self.common_read_len_cnt += 1; }
+
+        let offset = self.offset as usize;
+        let (begin, end) = (read.get_begin() as usize, read.get_end() as usize);
+
+        if end <= offset { return; }
+
+        let (begin, end) = (begin.max(offset) - offset, end - offset);
+
+        let mqual = read.get_mqual();
+
+        if mqual >= mapq.low_mapq { self.raw_window.accumulate(begin, end, 1); }
+
+        if read.check_is_split_read() { return; }
+
+        if mqual < mapq.low_mapq
+        {
+            self.low_mq_window.accumulate(begin, end, 1);
+        }
+
+        for (cutoff, window) in self.mapq_tiers.iter_mut()
+        {
+            if mqual < *cutoff { window.accumulate(begin, end, 1); }
+        }
+
+        self.corrected_window.accumulate(begin, end, 1);
+    }
+
+    pub fn new<'a, IType, AType>(bam:&'a IType, filter: &ReadFilter, mapq: &MapqConfig) -> Result<Scanner, ()>
         where AType : AlignmentType,
               IType : Input<'a, AType>
     {
-        let size = bam.size();
-        
+        let mut ret = Self::empty(bam.get_chrom(), bam.size(), bam.get_offset(), mapq);
+
+        for read in bam.try_iter()?.filter(|read| filter.accept(read))
+        {
+            ret.add_read(&read, mapq);
+        }
+
+        return Ok(ret);
+    }
+
+    /* Scan the reads of a contig from the stream of the whole alignment file. The reads are
+     * borrowed from the buffer of the stream one at a time */
+    pub fn from_stream(reads: &mut ContigReads, filter: &ReadFilter, mapq: &MapqConfig) -> Result<Scanner, ()>
+    {
+        let mut ret = Self::empty(reads.chrom(), reads.size(), 0, mapq);
+
+        while let Some(read) = reads.next_read()?
+        {
+            if filter.accept(&read) { ret.add_read(&read, mapq); }
+        }
+
+        return Ok(ret);
+    }
+
+    /* Build the scanner from the precomputed per-base coverage instead of the reads. The windows
+     * count the reads, so the depth is turned into the pseudo reads of the given read length.
+     * The MAPQ 0 coverage is optional, it makes the low MAPQ depth and it's excluded from the
+     * raw depth */
+    pub fn from_coverage(chrom: &str, size: usize, read_len: u32, total: &mut CoverageReader, mapq0: Option<&mut CoverageReader>) -> Result<Scanner, ()>
+    {
         let mut ret = Scanner {
             corrected_window : Window::<i32>::new(size),
             low_mq_window    : Window::<i32>::new(size),
             raw_window       : Window::<i32>::new(size),
-            common_read_len  : 0,
-            common_read_len_cnt: 0,
-            chrom            : String::from(bam.get_chrom()).into_boxed_str(),
+            mapq_tiers       : Vec::new(),
+            common_read_len  : read_len,
+            common_read_len_cnt: 1,
+            chrom            : String::from(chrom).into_boxed_str(),
             size: size as u32,
+            offset: 0,
         };
 
-        for read in bam.try_iter()?
+        let mut low_mq_intervals = Vec::new();
+
+        if let Some(mapq0) = mapq0
         {
-           if ret.common_read_len != read.get_length() 
-           {
-               if ret.common_read_len_cnt > 0 
-               {
-                   ret.common_read_len_cnt -= 1
-               }
-               else
-               {
-                   ret.common_read_len_cnt += 1;
-               }
-
-               if ret.common_read_len_cnt == 0
-               {
-                   ret.common_read_len = read.get_length();
-               }
-           } else { ret.common_read_len_cnt += 1; }
-
-           let (begin, end) = (read.get_begin() as usize, read.get_end() as usize);
-
-           if read.get_mqual() != 0 { ret.raw_window.accumulate(begin, end, 1); }
-
-           if read.check_is_split_read() { continue; }
-
-           if read.get_mqual() == 0 
-           {
-               ret.low_mq_window.accumulate(begin, end, 1);
-           }
-
-           ret.corrected_window.accumulate(begin, end, 1);
+            mapq0.read_contig(chrom, |begin, end, depth| low_mq_intervals.push((begin, end, depth.max(0) as usize)))?;
         }
 
+        let (corrected, raw, low_mq) = (&mut ret.corrected_window, &mut ret.raw_window, &mut ret.low_mq_window);
+        let mut raw_reads = PseudoReads::new(read_len as usize);
+        let mut low_mq_reads = PseudoReads::new(read_len as usize);
+        let mut idx = 0;
+
+        total.read_contig(chrom, |begin, end, depth| {
+            for pos in begin..end.min(size)
+            {
+                while idx < low_mq_intervals.len() && low_mq_intervals[idx].1 <= pos { idx += 1; }
+
+                let depth = depth.max(0) as usize;
+                let low_mq_depth = match low_mq_intervals.get(idx) {
+                    Some((begin, _, low_mq_depth)) if *begin <= pos => depth.min(*low_mq_depth),
+                    _ => 0
+                };
+
+                raw_reads.put(pos, depth - low_mq_depth, |begin, end| {
+                    raw.accumulate(begin, end, 1);
+                    corrected.accumulate(begin, end, 1);
+                });
+
+                low_mq_reads.put(pos, low_mq_depth, |begin, end| {
+                    low_mq.accumulate(begin, end, 1);
+                    corrected.accumulate(begin, end, 1);
+                });
+            }
+        })?;
+
         return Ok(ret);
     }
 
     pub fn chrom_size(&self) -> u32 {
         self.size
     }
+
+    /* The parameters that affect the scan result, a dump produced with different parameters
+     * can't be reused */
+    pub fn scan_params(filter: &ReadFilter, mapq: &MapqConfig) -> Vec<(&'static str, String)>
+    {
+        return vec![
+            ("mapq", mapq.describe()),
+            ("split_read", "raw_only".to_string()),
+            ("read_filter", filter.describe()),
+        ];
+    }
 }
 #[cfg(test)]
 pub mod mock_bam {
@@ -188,6 +403,7 @@ pub mod mock_bam {
         fn check_is_split_read(&self) -> bool { self.split }
         fn get_mqual(&self) -> u32 { self.qual }
         fn get_qpos(&self) -> (u32, u32) { (self.begin, self.end) }
+        fn get_flags(&self) -> u16 { 0 }
     }
 
     impl <'a> Input<'a, &'a TestAlignment> for (usize, Vec<TestAlignment>) {
@@ -224,7 +440,7 @@ mod scanner_test {
         //    eeeee
         // 245543210000
 
-        let scanner = Scanner::new(&my_bam)?;
+        let scanner = Scanner::new(&my_bam, &ReadFilter::default(), &MapqConfig::default())?;
 
         assert_eq!(scanner.get_common_read_length(), 5);
 
@@ -236,4 +452,91 @@ mod scanner_test {
 
         return Ok(());
     }
+
+    #[test]
+    fn test_scanner_dump() -> Result<(), std::io::Error>
+    {
+        let my_bam = (10, vec![ 
+            TestAlignment{begin: 1, end: 6, split: false, qual: 100 },
+            TestAlignment{begin: 2, end: 7, split: false, qual: 0 },
+            TestAlignment{begin: 0, end: 10, split: true, qual: 100},
+        ]);
+
+        let scanner = Scanner::new(&my_bam, &ReadFilter::default(), &MapqConfig::default()).unwrap();
+
+        for encoding in [DumpEncoding::Raw, DumpEncoding::Compressed].iter()
+        {
+            let mut buf = Vec::new();
+            scanner.try_dump(&mut buf, *encoding)?;
+
+            let loaded = Scanner::try_load(&mut &buf[0..])?;
+
+            assert_eq!(loaded.get_chrom(), "Chr1");
+            assert_eq!(loaded.chrom_size(), 10);
+            assert_eq!(loaded.get_common_read_length(), scanner.get_common_read_length());
+            assert_eq!(loaded.get_corrected().iter::<i32>(1).collect::<Vec<i32>>(), scanner.get_corrected().iter::<i32>(1).collect::<Vec<i32>>());
+            assert_eq!(loaded.get_low_mq_window().iter::<i32>(1).collect::<Vec<i32>>(), scanner.get_low_mq_window().iter::<i32>(1).collect::<Vec<i32>>());
+            assert_eq!(loaded.get_raw_window().iter::<i32>(1).collect::<Vec<i32>>(), scanner.get_raw_window().iter::<i32>(1).collect::<Vec<i32>>());
+
+            /* The corrupted element count and payload length of the first window, which follows
+             * the magic, version, header section and the number of windows */
+            let window_ofs = 8 + 4 + 8 + (4 + 4 + 16) + 4;
+            for (ofs, len) in [(window_ofs, 8), (window_ofs + 12, 8)].iter()
+            {
+                let mut corrupted = buf.clone();
+                corrupted[*ofs..*ofs + *len].iter_mut().for_each(|b| *b = 0xff);
+                assert!(Scanner::try_load(&mut &corrupted[0..]).is_err());
+            }
+
+            buf.truncate(buf.len() - 1);
+            assert!(Scanner::try_load(&mut &buf[0..]).is_err());
+        }
+
+        return Ok(());
+    }
+
+    #[test]
+    fn test_mapq_tiers() -> Result<(), std::io::Error>
+    {
+        let my_bam = (10, vec![ 
+            TestAlignment{begin: 0, end: 4, split: false, qual: 0 },
+            TestAlignment{begin: 2, end: 6, split: false, qual: 5 },
+            TestAlignment{begin: 4, end: 8, split: false, qual: 30 },
+        ]);
+
+        let mapq = MapqConfig { low_mapq: 10, tiers: vec![1, 20] };
+        let scanner = Scanner::new(&my_bam, &ReadFilter::default(), &mapq).unwrap();
+
+        assert_eq!(scanner.get_low_mq_window().iter::<i32>(1).collect::<Vec<i32>>(), vec![1,1,2,2,1,1,0,0,0,0]);
+        assert_eq!(scanner.get_raw_window().iter::<i32>(1).collect::<Vec<i32>>(),    vec![0,0,0,0,1,1,1,1,0,0]);
+
+        let tiers = scanner.get_mapq_tiers();
+        assert_eq!(tiers.len(), 2);
+        assert_eq!(tiers[0].0, 1);
+        assert_eq!(tiers[0].1.iter::<i32>(1).collect::<Vec<i32>>(), vec![1,1,1,1,0,0,0,0,0,0]);
+        assert_eq!(tiers[1].1.iter::<i32>(1).collect::<Vec<i32>>(), vec![1,1,2,2,1,1,0,0,0,0]);
+
+        let mut buf = Vec::new();
+        scanner.try_dump(&mut buf, DumpEncoding::Compressed)?;
+        let loaded = Scanner::try_load(&mut &buf[0..])?;
+
+        assert_eq!(loaded.get_mapq_tiers().len(), 2);
+        assert_eq!(loaded.get_mapq_tiers()[1].0, 20);
+        assert_eq!(loaded.get_mapq_tiers()[1].1.iter::<i32>(1).collect::<Vec<i32>>(), tiers[1].1.iter::<i32>(1).collect::<Vec<i32>>());
+
+        /* The dumps of any other version are rejected */
+        let with_version = |mut buf: Vec<u8>, version: u32| {
+            buf[8..12].copy_from_slice(&version.to_le_bytes());
+            let len = buf.len();
+            let crc = crate::dump::crc32(&buf[0..len - 4]);
+            buf[len - 4..].copy_from_slice(&crc.to_le_bytes());
+            buf
+        };
+
+        assert!(Scanner::try_load(&mut &with_version(buf.clone(), crate::dump::DUMP_VERSION)[0..]).is_ok());
+        assert!(Scanner::try_load(&mut &with_version(buf.clone(), 0)[0..]).is_err());
+        assert!(Scanner::try_load(&mut &with_version(buf, crate::dump::DUMP_VERSION + 1)[0..]).is_err());
+
+        return Ok(());
+    }
 }
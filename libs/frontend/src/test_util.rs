@@ -0,0 +1,38 @@
+/* The helpers shared by the tests */
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/* A file under the temp directory, the name is unique across the tests running in parallel and
+ * the file is removed when it's dropped, even if the test fails */
+pub struct TempFile {
+    path: String,
+}
+
+impl TempFile {
+    pub fn new(name: &str) -> Self
+    {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let path: PathBuf = std::env::temp_dir().join(format!("limo-{}-{}-{}", name, std::process::id(), id));
+        return TempFile { path: path.to_str().unwrap().to_string() };
+    }
+
+    /* The file next to this one, named by appending the suffix */
+    pub fn sibling(&self, suffix: &str) -> Self
+    {
+        return TempFile { path: format!("{}{}", self.path, suffix) };
+    }
+
+    pub fn path(&self) -> &str
+    {
+        return self.path.as_str();
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self)
+    {
+        std::fs::remove_file(&self.path).unwrap_or(());
+    }
+}
@@ -1,6 +1,6 @@
 use std::ops::{Add, Sub};
 use std::io::{Read, Write};
-use std::slice;
+use crate::dump::{DumpWriter, DumpReader, DumpEncoding, invalid_data, compress_i32, decompress_i32, max_compressed_size};
 
 #[allow(dead_code)]
 pub struct Window<T> where
@@ -19,44 +19,6 @@ impl <T> Window<T> where
     T : Add<Output = T>,
     T : Sub<Output = T>
 {
-    pub fn try_dump<TOut:Write>(&self, fp:&mut TOut) -> Result<(), std::io::Error>
-    {
-        fp.write_all(unsafe { slice::from_raw_parts((&self.acc.len() as *const usize) as *const u8, std::mem::size_of::<usize>()) })?;
-        fp.write_all(unsafe { slice::from_raw_parts(self.acc.as_ptr() as *const u8, std::mem::size_of::<T>() * self.acc.len()) })?;
-        fp.write_all(unsafe { slice::from_raw_parts(self.ext.as_ptr() as *const u8, std::mem::size_of::<T>() * self.acc.len()) })?;
-
-        return Ok(());
-    }
-
-    pub fn try_load<TIn:Read>(fp:&mut TIn) -> Result<Self, std::io::Error>
-    {
-        let mut buffer = 0usize;
-        let buffer_ptr = &mut buffer as *mut usize;
-
-        let size = fp.read(unsafe{ slice::from_raw_parts_mut(buffer_ptr as *mut u8, std::mem::size_of::<usize>())})?;
-
-        if size != std::mem::size_of::<usize>()
-        { 
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "unexpected header size")); 
-        }
-
-        let mut ret = Self {
-            acc: vec![T::default(); buffer],
-            ext: vec![T::default(); buffer]
-        };
-
-        let acc = fp.read(unsafe{slice::from_raw_parts_mut(ret.acc.as_mut_ptr() as *mut u8, std::mem::size_of::<T>() * buffer)})?;
-        let ext = fp.read(unsafe{slice::from_raw_parts_mut(ret.ext.as_mut_ptr() as *mut u8, std::mem::size_of::<T>() * buffer)})?;
-
-        if acc != buffer * std::mem::size_of::<T>() ||
-           ext != buffer * std::mem::size_of::<T>()
-        {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "cannot read the data block"));
-        }
-
-        return Ok(ret);
-    }
-
     pub fn new(range:usize) -> Self 
     {
         Self {
@@ -89,6 +51,94 @@ impl <T> Window<T> where
     }
 }
 
+impl Window<i32> {
+    pub fn try_dump<TOut:Write>(&self, fp:&mut DumpWriter<TOut>) -> Result<(), std::io::Error>
+    {
+        fp.write_u64(self.acc.len() as u64)?;
+        fp.write_u32(fp.encoding() as u32)?;
+
+        match fp.encoding() {
+            DumpEncoding::Raw => {
+                fp.write_u64((std::mem::size_of::<i32>() * self.acc.len() * 2) as u64)?;
+                fp.write_i32_slice(&self.acc[0..])?;
+                fp.write_i32_slice(&self.ext[0..])?;
+            },
+            DumpEncoding::Compressed => {
+                let acc = compress_i32(&self.acc[0..])?;
+                let ext = compress_i32(&self.ext[0..])?;
+                fp.write_u64((std::mem::size_of::<u64>() + acc.len() + ext.len()) as u64)?;
+                fp.write_u64(acc.len() as u64)?;
+                fp.write_bytes(&acc[0..])?;
+                fp.write_bytes(&ext[0..])?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /* Load the window of a chromosome of the given length, which has length + 1 elements. The
+     * sizes from the file are checked against it before anything is allocated */
+    pub fn try_load<TIn:Read>(fp:&mut DumpReader<TIn>, length: usize) -> Result<Self, std::io::Error>
+    {
+        let size = fp.read_u64()?;
+        let encoding = fp.read_u32()?;
+        let payload_size = fp.read_u64()?;
+
+        if size != length as u64 + 1
+        {
+            return Err(invalid_data(format!("the window size {} doesn't match the chromosome size {}", size, length)));
+        }
+
+        let size = size as usize;
+
+        match DumpEncoding::from_u32(encoding) {
+            Some(DumpEncoding::Raw) => {
+                if Some(payload_size) != (std::mem::size_of::<i32>() as u64).checked_mul(size as u64).and_then(|n| n.checked_mul(2))
+                {
+                    return Err(invalid_data(format!("malformed window section, {} elements in {} bytes", size, payload_size)));
+                }
+
+                let mut ret = Self {
+                    acc: vec![0; size],
+                    ext: vec![0; size]
+                };
+
+                fp.read_i32_slice(&mut ret.acc[0..])?;
+                fp.read_i32_slice(&mut ret.ext[0..])?;
+
+                return Ok(ret);
+            },
+            Some(DumpEncoding::Compressed) => {
+                if payload_size > max_compressed_size(size) as u64
+                {
+                    return Err(invalid_data(format!("malformed window section, {} elements in {} bytes", size, payload_size)));
+                }
+
+                let payload = fp.read_vec(payload_size as usize)?;
+
+                let acc_size = payload.get(0..8).map_or(usize::max_value(), |b| {
+                    u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as usize
+                });
+
+                if acc_size.saturating_add(8) > payload.len()
+                {
+                    return Err(invalid_data("malformed compressed window section".to_string()));
+                }
+
+                let ofs = acc_size + 8;
+
+                return Ok(Self {
+                    acc: decompress_i32(&payload[8..ofs], size)?,
+                    ext: decompress_i32(&payload[ofs..], size)?
+                });
+            },
+            None => {
+                return Err(invalid_data(format!("unknown window encoding {}", encoding)));
+            }
+        }
+    }
+}
+
 pub struct WindowIter<'a, T, R : From<T>> where
     T : Sized + Default + Clone,
     T : Add<Output = T>,
@@ -5,7 +5,9 @@ use frontend::depth_model::DepthModel;
 use frontend::frontend::{Frontend, Event, Side};
 use frontend::histogram::Histogram;
 use frontend::bamfile::BamFile;
+use frontend::read_filter::ReadFilter;
 use frontend::window::Window;
+use frontend::gc::GcCorrection;
 use std::cmp::Ord;
 
 pub struct EdgeDetector<'a, DM:DepthModel + 'a> {
@@ -15,13 +17,17 @@ pub struct EdgeDetector<'a, DM:DepthModel + 'a> {
     histogram: Histogram,
     raw_dep  : Box<Vec<i32>>,
     lmq_dep  : Box<Vec<i32>>,
+    tiers    : &'a [(u32, Window<i32>)],
     target_copy_num: Vec<u32>,
     bamfile : Option<BamFile>,
     pv_threshold: f64,
+    offset  : u32,
+    read_filter: ReadFilter,
+    gc_correction: Option<&'a GcCorrection>,
     phantom: PhantomData<&'a DM>
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Variant<'a> {
     pub chrom: &'a str,   
     pub left_pos: u32,
@@ -31,7 +37,18 @@ pub struct Variant<'a> {
     pub sd       : f64,
     pub pv_score : f64,
     pub lmq_mean : f64,
-    pub boundary : bool
+    pub boundary : bool,
+    /* (cutoff, mean of the normalized depth of the reads with MAPQ lower than cutoff) */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mapq_tiers: Vec<(u32, f64)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters  : Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excluded_frac: Option<f64>,
+    /* The depth level estimated by the model, the copy number is then the model's estimation
+     * instead of one of the target copy numbers */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<f64>
 }
 
 impl <'a> Variant<'a> {
@@ -43,7 +60,7 @@ impl <'a> Variant<'a> {
 
 impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
     #[allow(dead_code)]
-    pub fn new(frontend:&'a Frontend<DM>, scan_size: u32, copy_nums: &[u32], alignment: Option<(&str, Option<&str>, u32, f64)>) -> Self 
+    pub fn new(frontend:&'a Frontend<DM>, scan_size: u32, copy_nums: &[u32], alignment: Option<(&str, Option<&str>, u32, f64)>, read_filter: &ReadFilter) -> Self 
     {
         let raw_dep: Box<Vec<i32>> =  Box::new(frontend.get_scanner().get_raw_window().iter(1).collect());
         let lmq_dep: Box<Vec<i32>> = Box::new(frontend.get_scanner().get_low_mq_window().iter(1).collect());
@@ -58,9 +75,13 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
             scan_size,
             raw_dep,
             lmq_dep,
+            tiers: frontend.get_scanner().get_mapq_tiers(),
             histogram,
             target_copy_num,
             pv_threshold: alignment.iter().fold(0.0, |_d,v| v.3),
+            offset: frontend.get_scanner().get_offset(),
+            read_filter: read_filter.clone(),
+            gc_correction: frontend.get_gc_correction(),
             phantom: PhantomData,
             bamfile: if let Some((path, refer, chrom, _)) = alignment {
                 Some(BamFile::new(path, chrom, refer).unwrap())
@@ -148,6 +169,13 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
         return None;
     }
 
+    /* The normalized depth at the position, divided by the expected depth of the GC content */
+    fn normalized_depth(&mut self, dep: i32, pos: u32) -> f64
+    {
+        let val = self.histogram.normalize(dep as u32);
+        return self.gc_correction.map_or(val, |gc| val / gc.factor(pos));
+    }
+
     fn compute_norms(&mut self, left:u32, right:u32) -> (f64, f64, f64)
     {
         let len = (right - left) as f64;
@@ -156,10 +184,10 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
         let mut lmq_avg = 0f64;
         for pos in left..right
         {
-            let val = self.histogram.normalize(self.raw_dep[pos as usize] as u32);
+            let val = self.normalized_depth(self.raw_dep[pos as usize], pos);
             norm1 += val;
             norm2 += val * val;
-            lmq_avg += self.histogram.normalize(self.lmq_dep[pos as usize] as u32);
+            lmq_avg += self.normalized_depth(self.lmq_dep[pos as usize], pos);
         }
 
         norm1 /= len;
@@ -168,9 +196,59 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
         return (norm1, norm2 - norm1 * norm1, lmq_avg / len);
     }
 
-    fn compute_fr_correction<F:FnMut(u32, f64)>(&mut self, left: u32, right:u32, mut update:F) -> Result<bool,()> {
+    /* Fill in the mean depth of each MAPQ tier inside the variants, normalized and corrected
+     * like the raw depth. Each tier window is walked once, and the mean of a variant comes from
+     * the cumulative depth at its boundaries */
+    pub fn annotate_mapq_tiers(&mut self, variants: &mut [Variant])
+    {
+        let size = self.raw_dep.len();
+        let range = |sv: &Variant| (sv.left_pos as usize, (sv.right_pos as usize).min(size));
+
+        let mut bounds:Vec<_> = variants.iter().map(range).filter(|(left, right)| left < right).flat_map(|(left, right)| vec![left, right]).collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        if bounds.is_empty() { return; }
+
+        let tiers = self.tiers;
+
+        for (cutoff, window) in tiers.iter()
+        {
+            let mut cumulative = vec![0.0; bounds.len()];
+            let (mut sum, mut next) = (0.0, 0);
+
+            for (pos, dep) in window.iter(1).enumerate()
+            {
+                while next < bounds.len() && bounds[next] == pos { cumulative[next] = sum; next += 1; }
+
+                if next == bounds.len() { break; }
+
+                sum += self.normalized_depth(dep, pos as u32);
+            }
+
+            while next < bounds.len() { cumulative[next] = sum; next += 1; }
+
+            let at = |pos| bounds.binary_search(&pos).map_or(0.0, |idx| cumulative[idx]);
+
+            for sv in variants.iter_mut()
+            {
+                let (left, right) = range(sv);
+
+                if left >= right { continue; }
+
+                sv.mapq_tiers.push((*cutoff, (at(right) - at(left)) / (right - left) as f64));
+            }
+        }
+    }
+
+    /* For the gains only the FR pairs make a fragment, since the RF pairs across the junction
+     * of a tandem duplication cover the whole event in the reference coordinates */
+    fn compute_fr_correction<F:FnMut(u32, f64)>(&mut self, left: u32, right:u32, gain: bool, mut update:F) -> Result<bool,()> {
+        let offset = self.offset;
+        let read_filter = &self.read_filter;
         if let Some(ref mut bamfile) = self.bamfile {
-            let range = (left as usize, right as usize);
+            /* The scanner positions are relative to the offset, but the alignment file isn't */
+            let range = ((left + offset) as usize, (right + offset) as usize);
             
             let iter = bamfile.try_iter_range(range.0, range.1)?;
 
@@ -180,9 +258,11 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
             for read in iter 
             {
                 if read.mqual() == 0 { continue; }
+                if !read_filter.accept(&read) { continue; }
+                /* Only the paired reads with both ends mapped make a fragment */
                 if read.get_flags() & 0x80d != 1 { continue; }
                 if read.begin() < range.0 as u32 || read.ref_begin() < range.0 as u32 { continue; }
-                if read.get_isize() > 0 
+                if read.get_isize() > 0 && (!gain || read.get_flags() & 0x30 == 0x20)
                 {
                     let beg = read.begin() - range.0 as u32;
                     let end = read.begin() + self.read_size + (read.get_isize() as u32) - range.0 as u32;
@@ -200,7 +280,7 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
                 window_r.accumulate((read.ref_begin() - range.0 as u32) as usize, (read.ref_end() - range.0 as u32) as usize, 1);
             }
             
-            let mut pos = range.0 as u32;
+            let mut pos = left;
     
             for (p,r) in window.iter::<i32>(1).zip(window_r.iter::<i32>(1)) {
 
@@ -221,6 +301,9 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
      * Since the depth correction can cancel out most of the systematic bais, the average
      * of the depth is assumed to be stable.
      *
+     * The score is positive when the event is on one side of the nearby region. For the gains
+     * it should be above the nearby region, so the deletion-like events score negative
+     *
      * Problem: This needs to revisit the bamfile and slow down the program 
      */
     fn pvalue_validation(&mut self, variant: &Variant) -> Result<f64,()>
@@ -234,7 +317,9 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
         
         let range = (variant.left_pos - length, variant.right_pos + length);
 
-        if self.compute_fr_correction(range.0, range.1, |pos, cor| {
+        let gain = variant.copy_num > 2;
+
+        if self.compute_fr_correction(range.0, range.1, gain, |pos, cor| {
             if pos >= range.0 + 1000 {
                 if pos < variant.left_pos || pos > variant.right_pos {
                     normal_depth_value.push(cor);
@@ -255,14 +340,21 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
 
                 let exclude_range = [event_depth_value[exclude_size], event_depth_value[event_depth_value.len() - exclude_size]];
 
-                let score = exclude_range.iter().map(|x| (match normal_depth_value[..].binary_search_by(|y| fcmp(y,x)) { 
+                let ranks:Vec<_> = exclude_range.iter().map(|x| (match normal_depth_value[..].binary_search_by(|y| fcmp(y,x)) { 
                     Err(idx) => idx, 
                     Ok(idx) => idx 
-                }) as f64 / (normal_depth_value.len() as f64));
+                }) as f64 / (normal_depth_value.len() as f64)).collect();
 
-                return Ok(score.fold(4.0, |s, x| {
+                let score = ranks.iter().fold(4.0, |s, x| {
                     s * (x - 0.5)
-                }));
+                });
+
+                if gain && ranks[0] + ranks[1] < 1.0
+                {
+                    return Ok(-score.abs());
+                }
+
+                return Ok(score);
             }
             else 
             {
@@ -277,6 +369,7 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
         let ret = Some(raw);
         
         let mut result = match ret {
+            Some(variant) if variant.level.is_some() => Some(variant),
             Some(mut variant) => if (0.5 * (copy_num as f64) - variant.mean).abs() < 0.2 { Some(variant) } else 
             {
                 (|| {
@@ -297,12 +390,11 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
         /* P-Value validation */
         if let Some(ref mut what) = result 
         {
-            if what.copy_num < 2 
+            if what.copy_num != 2 
             {
                 what.pv_score = self.pvalue_validation(what).unwrap_or(1.0);
 
             }
-            /* TODO: implement the PV score validation for dups as well */
         }
         
         if result.iter().fold(1.0, |_x,y| y.pv_score) < self.pv_threshold { result = None }
@@ -325,6 +417,14 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
         let mut ret:Option<Variant> = None;
         let copy_num = event.0.copy_num;
 
+        /* The level estimated by the model is the depth we expect inside the event, otherwise
+         * it's the level of the copy number */
+        let level = match (event.0.level, event.1.level) {
+            (Some(left), Some(right)) => Some((left + right) * 0.5),
+            (left, right) => left.or(right)
+        };
+        let target = level.unwrap_or(0.5 * (copy_num as f64));
+
         'outer: for (left_pos, left_rate) in (&left_edges[0..]).iter().take(5)
         {
             for (right_pos, right_rate) in (&right_edges[0..]).iter().take(5)
@@ -337,14 +437,14 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
                 let (avg, sd, lmq_avg) = self.compute_norms(*left_pos, *right_pos);
 
                 if ret.is_none() || 
-                   (avg - 0.5 * (copy_num as f64)).abs() < (ret.as_ref().unwrap().mean - 0.5 * (copy_num as f64)).abs() ||
-                   (avg - lmq_avg - 0.5 * (copy_num as f64)).abs() < (ret.as_ref().unwrap().mean - 0.5 * (copy_num as f64)).abs() ||
+                   (avg - target).abs() < (ret.as_ref().unwrap().mean - target).abs() ||
+                   (avg - lmq_avg - target).abs() < (ret.as_ref().unwrap().mean - target).abs() ||
                    ((avg - ret.as_ref().unwrap().mean).abs() < 1e-5 && 
                     (sd < ret.as_ref().unwrap().sd))
                 {
                     let mut best_avg = avg;
 
-                    if (avg - 0.5 * (copy_num as f64)).abs() < (best_avg - 0.5 * (copy_num as f64)).abs()
+                    if (avg - target).abs() < (best_avg - target).abs()
                     {
                         best_avg = avg;
                     }
@@ -358,6 +458,10 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
                         pv_score: 1.0,
                         boundary: true,
                         lmq_mean: lmq_avg,
+                        mapq_tiers: Vec::new(),
+                        filters: Vec::new(),
+                        excluded_frac: None,
+                        level,
                     });
 
                     break 'outer;
@@ -366,13 +470,13 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
         }
         
         if ret.is_some() &&
-           (0.5 * (copy_num as f64) - ret.as_ref().unwrap().mean).abs() > 0.2
+           (target - ret.as_ref().unwrap().mean).abs() > 0.2
         {
             let mut left = event.0.pos;
             let mut right = event.1.pos;
 
-            while (self.histogram.normalize(self.raw_dep[left as usize] as u32) - (copy_num as f64) * 0.5).abs() > 0.1 && left < right { left += 1 }
-            while (self.histogram.normalize(self.raw_dep[right as usize] as u32) - (copy_num as f64) * 0.5).abs() > 0.1 && left < right { right -= 1 }
+            while (self.normalized_depth(self.raw_dep[left as usize], left) - target).abs() > 0.1 && left < right { left += 1 }
+            while (self.normalized_depth(self.raw_dep[right as usize], right) - target).abs() > 0.1 && left < right { right -= 1 }
 
             if (right - left) * 2 > event.1.pos - event.0.pos && right - left > 200
             {
@@ -385,12 +489,16 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
                         chrom: self.chrom,
                         left_pos: left,
                         right_pos: right,
-                        copy_num: copy_num,
+                        copy_num,
                         mean: avg,
                         sd,
                         pv_score: 1.0,
                         boundary: false,
                         lmq_mean: lmq,
+                        mapq_tiers: Vec::new(),
+                        filters: Vec::new(),
+                        excluded_frac: None,
+                        level,
                     });
                 }
             }
@@ -402,7 +510,8 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
             None
         };
         
-        if retry && result.is_some() && result.as_ref().unwrap().pv_score < 0.0
+        /* The estimated level is already the model's copy number, so we don't try the other targets */
+        if retry && level.is_none() && result.is_some() && result.as_ref().unwrap().pv_score < 0.0
         {
             let mut new_param = event.clone();
             let cur_copy_num = copy_num;
@@ -447,6 +556,10 @@ impl <'a, DM:DepthModel + 'a> EdgeDetector<'a, DM> {
                         pv_score: data.pv_score,
                         boundary: data.boundary,
                         lmq_mean: data.lmq_mean,
+                        mapq_tiers: Vec::new(),
+                        filters: Vec::new(),
+                        excluded_frac: None,
+                        level: data.level,
                     });
                 }
             }
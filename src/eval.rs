@@ -0,0 +1,462 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use clap::ArgMatches;
+use log::{info, error};
+
+use crate::regions::RegionSet;
+use frontend::coverage::GzLines;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum CnClass {
+    Homo,
+    Hemi,
+    Dup,
+    Unknown
+}
+
+impl CnClass {
+    fn from_copy_num(copy_num: u32) -> Self
+    {
+        match copy_num {
+            0 => CnClass::Homo,
+            1 => CnClass::Hemi,
+            2 => CnClass::Unknown,
+            _ => CnClass::Dup
+        }
+    }
+
+    fn name(&self) -> &'static str
+    {
+        match self {
+            CnClass::Homo => "homo",
+            CnClass::Hemi => "hemi",
+            CnClass::Dup => "dup",
+            CnClass::Unknown => "unknown"
+        }
+    }
+
+    /* The calls only match within the class they are reported in, the unknown class matches
+     * any of them */
+    fn compatible(&self, other: &CnClass) -> bool
+    {
+        match (self, other) {
+            (CnClass::Unknown, _) | (_, CnClass::Unknown) => true,
+            _ => self == other
+        }
+    }
+}
+
+/* The size bins, matching the ones we used in the run-eval script */
+const SIZE_BINS: [(u32, &str); 3] = [(1000, "<1k"), (5000, "1-5k"), (u32::MAX, ">5k")];
+
+fn size_bin(length: u32) -> &'static str
+{
+    return SIZE_BINS.iter().find(|(limit, _)| length < *limit).map_or(">5k", |(_, name)| name);
+}
+
+#[derive(Debug, Clone)]
+struct SvRecord {
+    chrom: String,
+    begin: u32,
+    end  : u32,
+    class: CnClass,
+    pass : bool,
+    matched: bool
+}
+
+impl SvRecord {
+    fn length(&self) -> u32 { self.end - self.begin }
+
+    fn reciprocal_overlap(&self, other: &SvRecord) -> f64
+    {
+        let (begin, end) = (self.begin.max(other.begin), self.end.min(other.end));
+        if begin >= end { return 0.0; }
+        let overlap = (end - begin) as f64;
+        return (overlap / self.length() as f64).min(overlap / other.length() as f64);
+    }
+}
+
+fn parse_info<'a>(info: &'a str, key: &str) -> Option<&'a str>
+{
+    return info.split(';').find_map(|kv| {
+        let mut kv = kv.splitn(2, '=');
+        if kv.next() == Some(key) { kv.next() } else { None }
+    });
+}
+
+fn parse_vcf_line(line: &str) -> Option<SvRecord>
+{
+    let fields:Vec<_> = line.split('\t').collect();
+    if fields.len() < 8 { return None; }
+
+    let pos = u32::from_str(fields[1]).ok()?;
+    let (reference, alt, info) = (fields[3], fields[4], fields[7]);
+
+    let sv_type = parse_info(info, "SVTYPE").map(|s| s.to_string()).unwrap_or_else(|| {
+        if alt.starts_with('<') { alt.trim_matches(|c| c == '<' || c == '>').to_string() }
+        else if reference.len() > alt.len() { "DEL".to_string() }
+        else { "INS".to_string() }
+    });
+
+    let end = if let Some(end) = parse_info(info, "END").and_then(|s| u32::from_str(s).ok()) { end }
+    else if let Some(len) = parse_info(info, "SVLEN").and_then(|s| i64::from_str(s).ok()) { pos + len.unsigned_abs() as u32 }
+    else { pos + reference.len() as u32 - 1 };
+
+    /* The genotype and copy number in the first sample */
+    let sample_field = |key: &str| -> Option<&str> {
+        let idx = fields.get(8)?.split(':').position(|k| k == key)?;
+        fields.get(9)?.split(':').nth(idx)
+    };
+
+    let class = if let Some(copy_num) = sample_field("CN").and_then(|s| u32::from_str(s).ok()) {
+        CnClass::from_copy_num(copy_num)
+    } else if sv_type.starts_with("DUP") {
+        CnClass::Dup
+    } else if sv_type.starts_with("DEL") {
+        match sample_field("GT").map(|gt| gt.replace('|', "/")).as_deref() {
+            Some("1/1") => CnClass::Homo,
+            Some("0/1") | Some("1/0") => CnClass::Hemi,
+            _ => CnClass::Unknown
+        }
+    } else {
+        return None;
+    };
+
+    if end <= pos { return None; }
+
+    return Some(SvRecord {
+        chrom: fields[0].to_string(),
+        begin: pos,
+        end,
+        class,
+        pass: fields[6] == "PASS" || fields[6] == ".",
+        matched: false
+    });
+}
+
+/* Either the Limo BED output with the JSON annotation, or a BED with an optional copy number
+ * or variant class column */
+fn parse_bed_line(line: &str) -> Option<SvRecord>
+{
+    let fields:Vec<_> = line.split('\t').collect();
+    if fields.len() < 3 { return None; }
+
+    let (begin, end) = (u32::from_str(fields[1]).ok()?, u32::from_str(fields[2]).ok()?);
+
+    if end <= begin { return None; }
+
+    let mut pass = true;
+
+    let class = match fields.get(3).map(|s| s.trim()) {
+        Some(annotation) if annotation.starts_with('{') => {
+            let json:serde_json::Value = serde_json::from_str(annotation).ok()?;
+            pass = json.get("filters").and_then(|f| f.as_array()).is_none_or(|f| f.is_empty());
+            json.get("copy_num").and_then(|cn| cn.as_u64()).map_or(CnClass::Unknown, |cn| CnClass::from_copy_num(cn as u32))
+        },
+        Some(annotation) => {
+            if let Ok(copy_num) = u32::from_str(annotation) { CnClass::from_copy_num(copy_num) }
+            else {
+                match annotation.to_lowercase().as_str() {
+                    "homo" => CnClass::Homo,
+                    "hemi" => CnClass::Hemi,
+                    "dup" => CnClass::Dup,
+                    _ => CnClass::Unknown
+                }
+            }
+        },
+        None => CnClass::Unknown
+    };
+
+    return Some(SvRecord {
+        chrom: fields[0].to_string(),
+        begin,
+        end,
+        class,
+        pass,
+        matched: false
+    });
+}
+
+fn load_records(path: &str) -> Result<Vec<SvRecord>, ()>
+{
+    /* The truth sets are usually compressed, like *.vcf.gz */
+    let lines = GzLines::open(path)?;
+
+    let mut is_vcf = path.ends_with(".vcf") || path.ends_with(".vcf.gz");
+    let mut ret = Vec::new();
+
+    for line in lines
+    {
+        if line.starts_with("##fileformat=VCF") { is_vcf = true; }
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") { continue; }
+
+        let record = if is_vcf { parse_vcf_line(&line) } else { parse_bed_line(&line) };
+
+        if let Some(record) = record { ret.push(record); }
+    }
+
+    info!("Loaded {} records from {}", ret.len(), path);
+
+    return Ok(ret);
+}
+
+/* Each call matches at most one truth record and the other way around, the pairs with the best
+ * reciprocal overlap are matched first */
+fn match_records(calls: &mut [SvRecord], truth: &mut [SvRecord], min_overlap: f64)
+{
+    let key = |a:&SvRecord, b:&SvRecord| (&a.chrom, a.begin).cmp(&(&b.chrom, b.begin));
+    calls.sort_by(key);
+    truth.sort_by(key);
+
+    let max_length = truth.iter().fold(0, |len, sv| len.max(sv.length()));
+    let mut first = 0;
+    let mut pairs = Vec::new();
+
+    for (call_idx, call) in calls.iter().enumerate()
+    {
+        /* Both sides are sorted, so the truth records before first can not reach any of the
+         * remaining calls */
+        while first < truth.len() &&
+              (truth[first].chrom < call.chrom || (truth[first].chrom == call.chrom && truth[first].begin.saturating_add(max_length) <= call.begin))
+        {
+            first += 1;
+        }
+
+        for (truth_idx, sv) in truth.iter().enumerate().skip(first)
+        {
+            if sv.chrom != call.chrom || sv.begin >= call.end { break; }
+
+            let overlap = sv.reciprocal_overlap(call);
+
+            if sv.class.compatible(&call.class) && overlap >= min_overlap
+            {
+                pairs.push((overlap, call_idx, truth_idx));
+            }
+        }
+    }
+
+    pairs.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal).then((a.1, a.2).cmp(&(b.1, b.2))));
+
+    for (_, call_idx, truth_idx) in pairs
+    {
+        if !calls[call_idx].matched && !truth[truth_idx].matched
+        {
+            calls[call_idx].matched = true;
+            truth[truth_idx].matched = true;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stat {
+    truth: usize,
+    truth_matched: usize,
+    calls: usize,
+    calls_matched: usize
+}
+
+impl Stat {
+    fn precision(&self) -> f64 { if self.calls == 0 { 0.0 } else { self.calls_matched as f64 / self.calls as f64 } }
+    fn recall(&self) -> f64 { if self.truth == 0 { 0.0 } else { self.truth_matched as f64 / self.truth as f64 } }
+    fn f1(&self) -> f64
+    {
+        let (p, r) = (self.precision(), self.recall());
+        if p + r == 0.0 { 0.0 } else { 2.0 * p * r / (p + r) }
+    }
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), ()>
+{
+    let min_overlap = f64::from_str(matches.value_of("reciprocal-overlap").unwrap_or("0.5")).expect("Invalid overlap");
+
+    let mut calls = load_records(matches.value_of("calls").unwrap())?;
+    let mut truth = load_records(matches.value_of("truth").unwrap())?;
+
+    if matches.is_present("pass-only")
+    {
+        calls.retain(|sv| sv.pass);
+    }
+
+    if let Some(path) = matches.value_of("include-regions")
+    {
+        let regions = RegionSet::load(path)?;
+        let inside = |sv:&SvRecord| regions.overlap(&sv.chrom, sv.begin, sv.end) == sv.length();
+        calls.retain(&inside);
+        truth.retain(&inside);
+    }
+
+    match_records(&mut calls, &mut truth, min_overlap);
+
+    let stats = compute_stats(&calls[0..], &truth[0..]);
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    let write_err = |e| { error!("Unable to write the output: {:?}", e); };
+
+    writeln!(out, "#breakdown\tcategory\ttruth\ttruth_matched\tcalls\tcalls_matched\tprecision\trecall\tf1").map_err(write_err)?;
+
+    for ((breakdown, category), stat) in stats.iter()
+    {
+        writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}", breakdown, category,
+                 stat.truth, stat.truth_matched, stat.calls, stat.calls_matched,
+                 stat.precision(), stat.recall(), stat.f1()).map_err(write_err)?;
+    }
+
+    return Ok(());
+}
+
+/* The key is (breakdown, category), the "all" breakdown is the overall result */
+fn compute_stats(calls: &[SvRecord], truth: &[SvRecord]) -> BTreeMap<(&'static str, String), Stat>
+{
+    let mut stats = BTreeMap::<(&str, String), Stat>::new();
+
+    let categories = |sv:&SvRecord| vec![
+        ("all", "all".to_string()),
+        ("chrom", sv.chrom.clone()),
+        ("copy_num", sv.class.name().to_string()),
+        ("size", size_bin(sv.length()).to_string())
+    ];
+
+    for sv in truth.iter()
+    {
+        for key in categories(sv)
+        {
+            let stat = stats.entry(key).or_default();
+            stat.truth += 1;
+            if sv.matched { stat.truth_matched += 1; }
+        }
+    }
+
+    for sv in calls.iter()
+    {
+        for key in categories(sv)
+        {
+            let stat = stats.entry(key).or_default();
+            stat.calls += 1;
+            if sv.matched { stat.calls_matched += 1; }
+        }
+    }
+
+    return stats;
+}
+
+#[cfg(test)]
+mod eval_test {
+    use super::*;
+
+    fn record(chrom: &str, begin: u32, end: u32, copy_num: u32) -> SvRecord
+    {
+        return SvRecord { chrom: chrom.to_string(), begin, end, class: CnClass::from_copy_num(copy_num), pass: true, matched: false };
+    }
+
+    #[test]
+    fn test_parse_vcf_line()
+    {
+        let sv = parse_vcf_line("chr1\t1000\t.\tN\t<DEL>\t.\tPASS\tSVTYPE=DEL;END=3000;SVLEN=-2000\tGT:CN\t0/1:1").unwrap();
+        assert_eq!((sv.chrom.as_str(), sv.begin, sv.end, sv.class, sv.pass), ("chr1", 1000, 3000, CnClass::Hemi, true));
+
+        /* Without END and CN, the end comes from SVLEN and the class from GT */
+        let sv = parse_vcf_line("chr2\t500\t.\tN\t<DEL>\t.\tLowPvHomo\tSVLEN=-100\tGT\t1|1").unwrap();
+        assert_eq!((sv.begin, sv.end, sv.class, sv.pass), (500, 600, CnClass::Homo, false));
+
+        let sv = parse_vcf_line("chr2\t500\t.\tN\t<DUP>\t.\t.\tEND=900").unwrap();
+        assert_eq!((sv.end, sv.class, sv.pass), (900, CnClass::Dup, true));
+
+        /* The sequence resolved deletion spans the deleted bases after the anchor */
+        let sv = parse_vcf_line("chr3\t10\t.\tACGT\tA\t.\tPASS\t.").unwrap();
+        assert_eq!((sv.begin, sv.end, sv.class), (10, 13, CnClass::Unknown));
+
+        assert!(parse_vcf_line("chr1\t1000\t.\tA\tACGT\t.\tPASS\t.").is_none());
+        assert!(parse_vcf_line("chr1\t1000\t.\tN\t<DEL>\t.\tPASS\tEND=1000").is_none());
+        assert!(parse_vcf_line("chr1\tabc\t.\tN\t<DEL>\t.\tPASS\tEND=2000").is_none());
+        assert!(parse_vcf_line("chr1\t1000\t.\tN").is_none());
+    }
+
+    #[test]
+    fn test_parse_bed_line()
+    {
+        let sv = parse_bed_line("chr1\t1000\t3000\t{\"copy_num\":0,\"filters\":[\"MinSize\"]}").unwrap();
+        assert_eq!((sv.begin, sv.end, sv.class, sv.pass), (1000, 3000, CnClass::Homo, false));
+
+        assert_eq!(parse_bed_line("chr1\t1000\t3000\t3").unwrap().class, CnClass::Dup);
+        assert_eq!(parse_bed_line("chr1\t1000\t3000\tHemi").unwrap().class, CnClass::Hemi);
+        assert_eq!(parse_bed_line("chr1\t1000\t3000").unwrap().class, CnClass::Unknown);
+        assert!(parse_bed_line("chr1\t3000\t3000").is_none());
+        assert!(parse_bed_line("chr1\t3000").is_none());
+    }
+
+    #[test]
+    fn test_reciprocal_overlap()
+    {
+        let sv = record("chr1", 1000, 2000, 1);
+
+        /* Half-open intervals, the adjacent ones don't overlap */
+        assert_eq!(sv.reciprocal_overlap(&record("chr1", 2000, 3000, 1)), 0.0);
+        assert_eq!(sv.reciprocal_overlap(&record("chr1", 1999, 3000, 1)), 1.0 / 1001.0);
+        assert_eq!(sv.reciprocal_overlap(&record("chr1", 1500, 2500, 1)), 0.5);
+        assert_eq!(sv.reciprocal_overlap(&record("chr1", 1000, 4000, 1)), 1.0 / 3.0);
+        assert_eq!(sv.reciprocal_overlap(&sv), 1.0);
+    }
+
+    #[test]
+    fn test_precision_recall()
+    {
+        let mut truth = vec![
+            record("chr1", 1000, 2000, 1),
+            record("chr1", 5000, 8000, 0),
+            record("chr2", 1000, 1500, 3),
+            record("chr2", 9000, 20000, 1),
+        ];
+
+        let mut calls = vec![
+            /* Matched at exactly 50% overlap */
+            record("chr1", 1500, 2500, 1),
+            /* A hemizygous loss doesn't match a homozygous one */
+            record("chr1", 5100, 8000, 1),
+            /* A loss never matches a gain */
+            record("chr2", 1000, 1500, 1),
+            /* Not enough overlap */
+            record("chr2", 9000, 12000, 1),
+            record("chr3", 1000, 2000, 0),
+        ];
+
+        match_records(&mut calls, &mut truth, 0.5);
+
+        let stats = compute_stats(&calls[0..], &truth[0..]);
+
+        let all = &stats[&("all", "all".to_string())];
+        assert_eq!((all.truth, all.truth_matched, all.calls, all.calls_matched), (4, 1, 5, 1));
+        assert_eq!(all.precision(), 0.2);
+        assert_eq!(all.recall(), 0.25);
+        assert!((all.f1() - 2.0 / 9.0).abs() < 1e-9);
+
+        let chr2 = &stats[&("chrom", "chr2".to_string())];
+        assert_eq!((chr2.truth_matched, chr2.calls_matched), (0, 0));
+
+        let dup = &stats[&("copy_num", "dup".to_string())];
+        assert_eq!((dup.truth, dup.calls, dup.recall(), dup.precision()), (1, 0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_one_to_one()
+    {
+        /* The truth record goes to the call overlapping it best, the other call is a false positive */
+        let mut truth = vec![record("chr1", 1000, 2000, 1), record("chr1", 2000, 4000, 1)];
+        let mut calls = vec![
+            record("chr1", 1200, 2000, 1),
+            record("chr1", 1000, 2100, 1),
+            /* A gain never matches a loss */
+            record("chr1", 1000, 2000, 3),
+        ];
+
+        match_records(&mut calls, &mut truth, 0.5);
+
+        let matched:Vec<_> = calls.iter().map(|sv| (sv.begin, sv.end, sv.matched)).collect();
+        assert_eq!(matched, vec![(1000, 2100, true), (1000, 2000, false), (1200, 2000, false)]);
+        assert_eq!(truth.iter().map(|sv| sv.matched).collect::<Vec<_>>(), vec![true, false]);
+    }
+}
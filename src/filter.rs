@@ -0,0 +1,158 @@
+use crate::edge::Variant;
+
+/* The post-call filters, each of them is disabled unless the threshold is given.
+ * A variant failing a filter isn't dropped, it's tagged with the filter name instead */
+#[derive(Clone, Default)]
+pub struct VariantFilter {
+    /* The variant should be longer than this */
+    pub min_size: Option<u32>,
+    /* The pv_score of a heterozygous deletion should be higher than this */
+    pub hemi_pv: Option<f64>,
+    /* The pv_score of a homozygous deletion should be higher than this */
+    pub homo_pv: Option<f64>,
+    /* The pv_score of a duplication should be higher than this */
+    pub dup_pv: Option<f64>,
+    /* A variant without boundary support needs a pv_score higher than this, unless it's longer
+     * than boundary_size */
+    pub boundary_pv: Option<f64>,
+    pub boundary_size: u32,
+}
+
+pub const MIN_SIZE: &str = "MinSize";
+pub const LOW_PV_HEMI: &str = "LowPvHemi";
+pub const LOW_PV_HOMO: &str = "LowPvHomo";
+pub const LOW_PV_DUP: &str = "LowPvDup";
+pub const NO_BOUNDARY: &str = "NoBoundary";
+
+impl VariantFilter {
+    /* The list of enabled filters and their descriptions */
+    pub fn describe(&self) -> Vec<(&'static str, String)>
+    {
+        let mut ret = Vec::new();
+
+        if let Some(size) = self.min_size {
+            ret.push((MIN_SIZE, format!("Variant is not longer than {}bp", size)));
+        }
+
+        if let Some(pv) = self.hemi_pv {
+            ret.push((LOW_PV_HEMI, format!("Heterozygous deletion with pv_score not higher than {}", pv)));
+        }
+
+        if let Some(pv) = self.homo_pv {
+            ret.push((LOW_PV_HOMO, format!("Homozygous deletion with pv_score not higher than {}", pv)));
+        }
+
+        if let Some(pv) = self.dup_pv {
+            ret.push((LOW_PV_DUP, format!("Duplication with pv_score not higher than {}", pv)));
+        }
+
+        if let Some(pv) = self.boundary_pv {
+            ret.push((NO_BOUNDARY, format!("Variant without boundary support, pv_score not higher than {} and not longer than {}bp", pv, self.boundary_size)));
+        }
+
+        return ret;
+    }
+
+    pub fn apply(&self, sv: &mut Variant)
+    {
+        let length = sv.right_pos - sv.left_pos;
+
+        sv.filters.clear();
+
+        if self.min_size.is_some_and(|size| length <= size) {
+            sv.filters.push(MIN_SIZE.to_string());
+        }
+
+        if sv.copy_num == 1 && self.hemi_pv.is_some_and(|pv| sv.pv_score <= pv) {
+            sv.filters.push(LOW_PV_HEMI.to_string());
+        }
+
+        if sv.copy_num == 0 && self.homo_pv.is_some_and(|pv| sv.pv_score <= pv) {
+            sv.filters.push(LOW_PV_HOMO.to_string());
+        }
+
+        if sv.copy_num > 2 && self.dup_pv.is_some_and(|pv| sv.pv_score <= pv) {
+            sv.filters.push(LOW_PV_DUP.to_string());
+        }
+
+        if let Some(pv) = self.boundary_pv {
+            if !sv.boundary && sv.pv_score <= pv && length <= self.boundary_size {
+                sv.filters.push(NO_BOUNDARY.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod filter_test {
+    use super::*;
+
+    fn variant(length: u32, copy_num: u32, pv_score: f64, boundary: bool) -> Variant<'static>
+    {
+        return Variant {
+            chrom: "chr1",
+            left_pos: 1000,
+            right_pos: 1000 + length,
+            copy_num,
+            pv_score,
+            boundary,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn test_disabled_filters()
+    {
+        let mut sv = variant(10, 0, 0.0, false);
+        VariantFilter::default().apply(&mut sv);
+        assert!(sv.filters.is_empty());
+        assert!(VariantFilter::default().describe().is_empty());
+    }
+
+    #[test]
+    fn test_apply_filters()
+    {
+        let filter = VariantFilter {
+            min_size: Some(500),
+            hemi_pv: Some(0.3),
+            homo_pv: Some(0.1),
+            dup_pv: Some(0.2),
+            boundary_pv: Some(0.5),
+            boundary_size: 5000,
+        };
+
+        /* The thresholds are exclusive, a variant at the threshold fails */
+        let mut sv = variant(500, 1, 0.3, true);
+        filter.apply(&mut sv);
+        assert_eq!(sv.filters, vec![MIN_SIZE, LOW_PV_HEMI]);
+
+        let mut sv = variant(501, 1, 0.31, true);
+        filter.apply(&mut sv);
+        assert!(sv.filters.is_empty());
+
+        let mut sv = variant(1000, 0, 0.1, true);
+        filter.apply(&mut sv);
+        assert_eq!(sv.filters, vec![LOW_PV_HOMO]);
+
+        let mut sv = variant(1000, 3, 0.2, true);
+        filter.apply(&mut sv);
+        assert_eq!(sv.filters, vec![LOW_PV_DUP]);
+
+        /* The boundary filter is skipped for the long variants */
+        let mut sv = variant(5000, 1, 0.4, false);
+        filter.apply(&mut sv);
+        assert_eq!(sv.filters, vec![NO_BOUNDARY]);
+
+        let mut sv = variant(5001, 1, 0.4, false);
+        filter.apply(&mut sv);
+        assert!(sv.filters.is_empty());
+
+        /* The previous tags are replaced */
+        sv.right_pos = sv.left_pos + 100;
+        filter.apply(&mut sv);
+        assert_eq!(sv.filters, vec![MIN_SIZE, NO_BOUNDARY]);
+
+        let ids:Vec<_> = filter.describe().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![MIN_SIZE, LOW_PV_HEMI, LOW_PV_HOMO, LOW_PV_DUP, NO_BOUNDARY]);
+    }
+}
@@ -1,80 +1,442 @@
 #![feature(core_intrinsics)]
 mod edge;
 mod task;
+mod vcf;
+mod filter;
+mod regions;
+mod eval;
 
 
 use self::task::Task;
-use frontend::bamfile::BamFile;
+use self::vcf::VcfWriter;
+use self::filter::VariantFilter;
+use self::regions::RegionSet;
+use frontend::header::BamHeader;
+use frontend::bamfile::{BamFile, BamStream};
+use frontend::scanner::{Scanner, MapqConfig};
+use frontend::read_filter::ReadFilter;
+use frontend::container::{DumpContainer, Fingerprint};
+use frontend::coverage::{CoverageReader, load_fai};
+use frontend::frontend::ScoreThreshold;
+use frontend::models::ModelKind;
+use frontend::hmm::HmmParam;
+use frontend::gc::{GcCurve, window_depth};
 use clap::{App, load_yaml};
 use threadpool::ThreadPool;
 use regex::Regex;
 
 use std::str::FromStr;
+use std::collections::HashSet;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::io::Write;
 
-use log::{info,debug};
+use log::{info,debug,warn,error};
 
 fn main() -> Result<(), ()>
 {
     let options = load_yaml!("cli.yml");
     let matches = App::from_yaml(options).get_matches();
+
+    stderrlog::new()
+        .module(module_path!())
+        .module(frontend::get_module_path())
+        .verbosity(3)
+        .timestamp(stderrlog::Timestamp::Second)
+        .init()
+        .expect("Unable to initialize logging");
+
+    if let Some(eval_matches) = matches.subcommand_matches("eval")
+    {
+        return eval::run(eval_matches);
+    }
     
     let copy_nums = matches.value_of("copy-nums").unwrap();
-    let copy_nums:Vec<_> =  copy_nums.split(",").map(|s| u32::from_str_radix(s, 10).unwrap()).collect();
+    let copy_nums:Vec<_> =  copy_nums.split(",").map(|s| s.parse::<u32>().unwrap()).collect();
+
+    let window_size = matches.value_of("window-size").unwrap_or("300").parse::<u32>().unwrap();
+
+    let model_name = matches.value_of("model").unwrap_or("linear");
+    let model = ModelKind::from_name(model_name).ok_or_else(|| {
+        let names:Vec<_> = ModelKind::list().into_iter().map(|(name, _)| name).collect();
+        error!("Unknown depth model {}, the available models are {}", model_name, names.join(", "));
+    })?;
 
-    let window_size = u32::from_str_radix(matches.value_of("window-size").unwrap_or("300"), 10).unwrap();
-    let alignment = matches.value_of("alignment-file").unwrap();
+    let hmm = if matches.is_present("hmm") {
+        Some(HmmParam {
+            switch_prob: matches.value_of("hmm-switch-prob").map_or(HmmParam::default().switch_prob, |val| f64::from_str(val).expect("Invalid probability")),
+        })
+    } else { None };
 
-    let mut nthreads = matches.value_of("threads").map(|s| usize::from_str_radix(s, 10).unwrap()).unwrap_or(1);
+    /* The calibration takes another pass over the depth of each chromosome, so it's only done
+     * when the false positive rate is given */
+    let score_threshold = match (matches.value_of("score-threshold"), matches.value_of("score-fp-rate")) {
+        (Some("fixed"), _) | (None, None) => ScoreThreshold::Fixed,
+        (Some(val), _) => ScoreThreshold::Value(f64::from_str(val).expect("Invalid score threshold")),
+        (None, Some(rate)) => ScoreThreshold::Calibrated(f64::from_str(rate).expect("Invalid false positive rate")),
+    };
+    /* With the precomputed coverage, the alignment file is optional and only used for the header
+     * and the probability validation */
+    let coverage = matches.value_of("coverage");
+    let alignment = matches.value_of("alignment-file").unwrap_or("");
+
+    let mut nthreads = matches.value_of("threads").map(|s| s.parse::<usize>().unwrap()).unwrap_or(1);
 
 
     let include_pattern = Regex::new(matches.value_of("include").unwrap_or(r"^([Cc]hr)?[0-9XYxy]*$")).unwrap();
     let exclude_pattern = Regex::new(matches.value_of("exclude").unwrap_or(".^")).unwrap();
 
-    stderrlog::new()
-        .module(module_path!())
-        .module(frontend::get_module_path())
-        .verbosity(3)
-        .timestamp(stderrlog::Timestamp::Second)
-        .init()
-        .expect("Unable to initialize logging");
+    let output_vcf = matches.value_of("output-format") == Some("vcf");
+
+    /* The probability validation is on by default, "off" disables it */
+    let pv_threshold = match matches.value_of("prob-validate") {
+        None | Some("off") => 0.2,
+        Some(val) => f64::from_str(val).map_err(|_| { error!("Invalid probability validation threshold {}", val); })?,
+    };
+
+    let parse_threshold = |name:&str| matches.value_of(name).map(|val| f64::from_str(val).expect("Invalid threshold"));
+
+    let filter = VariantFilter {
+        min_size: matches.value_of("min-size").map(|val| val.parse::<u32>().expect("Invalid size")),
+        hemi_pv: parse_threshold("hemi-pv-threshold"),
+        homo_pv: parse_threshold("homo-pv-threshold"),
+        dup_pv: parse_threshold("dup-pv-threshold"),
+        boundary_pv: parse_threshold("boundary-pv-threshold"),
+        boundary_size: matches.value_of("boundary-min-size").unwrap_or("5000").parse::<u32>().expect("Invalid size"),
+    };
+
+    let parse_flags = |name, default| {
+        matches.value_of(name).map_or(Ok(default), ReadFilter::parse_flags).map_err(|_| { error!("Invalid flag mask for --{}", name); })
+    };
 
-    let target_list:Vec<_> = BamFile::list_chromosomes(alignment)?.into_iter().enumerate()
-        .filter(|(_, name)| include_pattern.is_match(&name) && !exclude_pattern.is_match(&name))
-        .map(|(idx, name)| {
-            debug!("Selected chromosome id={} name={}", idx, name);
-            idx as u32
-        }).collect(); 
+    let read_filter = ReadFilter {
+        include_flags: parse_flags("include-flags", 0)?,
+        exclude_flags: parse_flags("exclude-flags", ReadFilter::default().exclude_flags)?,
+        min_mapq: matches.value_of("min-mapq").unwrap_or("0").parse::<u32>().expect("Invalid MAPQ"),
+        proper_pair: matches.is_present("proper-pair"),
+    };
+
+    let mapq = MapqConfig {
+        low_mapq: matches.value_of("low-mapq").unwrap_or("1").parse::<u32>().expect("Invalid MAPQ"),
+        tiers: matches.value_of("mapq-tiers").map_or(Vec::new(), |val| {
+            let mut tiers:Vec<_> = val.split(",").map(|s| s.parse::<u32>().expect("Invalid MAPQ")).collect();
+            tiers.sort();
+            tiers.dedup();
+            tiers
+        }),
+    };
+
+    let exclude_regions = if let Some(path) = matches.value_of("exclude-regions") {
+        Some(Arc::new(RegionSet::load(path)?))
+    } else { None };
+
+    if let Some(reference) = matches.value_of("reference")
+    {
+        if !std::path::Path::new(reference).is_file()
+        {
+            error!("Cannot find the reference file {}", reference);
+            return Err(());
+        }
+    }
+
+    /* Without the index, for example the SAM file or the standard input, the alignment can
+     * only be read once in order, and the probability validation isn't possible */
+    let from_stdin = alignment == "-";
+    let has_alignment = !alignment.is_empty();
+    let has_index = has_alignment && BamFile::has_index(alignment);
+
+    if coverage.is_some()
+    {
+        if !has_index
+        {
+            warn!("No indexed alignment file is given, the probability validation is disabled for the coverage input");
+        }
+    }
+    else if !has_index && !from_stdin
+    {
+        warn!("The alignment file {} doesn't have an index, scanning it in a single pass without the probability validation", alignment);
+    }
+
+    let single_pass = coverage.is_none() && (matches.is_present("single-pass") || !has_index);
+
+    if coverage.is_some() && (matches.is_present("region") || matches.is_present("regions"))
+    {
+        error!("The targeted calling isn't supported with the coverage input");
+        return Err(());
+    }
+
+    if !has_index && (matches.is_present("region") || matches.is_present("regions"))
+    {
+        error!("The targeted calling requires an indexed alignment file");
+        return Err(());
+    }
+
+    /* In the single pass mode the alignment file is read once in this thread, each contig's
+     * scanner is handed to a worker as soon as the contig is complete */
+    let mut stream = if single_pass && !(matches.is_present("region") || matches.is_present("regions")) {
+        Some(BamStream::open(alignment, matches.value_of("reference"))?)
+    } else {
+        if single_pass { warn!("The single pass mode is ignored by the targeted calling"); }
+        None
+    };
+
+    let header_owned = if stream.is_none() && has_alignment { Some(BamHeader::read(alignment, matches.value_of("reference"))?) } else { None };
+    let header = stream.as_ref().map(|stream| stream.header()).or(header_owned.as_ref());
+
+    /* Without the alignment file the contigs are listed by the FASTA index of the reference */
+    let contigs = if let Some(header) = header { header.contigs() } else {
+        let reference = if let Some(reference) = matches.value_of("reference") { reference } else {
+            error!("The coverage input without the alignment file requires the reference");
+            return Err(());
+        };
+        load_fai(&format!("{}.fai", reference))?
+    };
+
+    if output_vcf
+    {
+        let sample_source = if has_alignment { alignment } else { coverage.unwrap_or("") };
+        let sample = if from_stdin { "SAMPLE".to_string() } else {
+            std::path::Path::new(sample_source).file_stem().map_or("SAMPLE".to_string(), |s| s.to_string_lossy().to_string())
+        };
+        let writer = if let Some(header) = header {
+            VcfWriter::new(header, filter.describe(), sample.as_str())
+        } else {
+            VcfWriter::with_contigs(contigs.clone(), filter.describe(), sample.as_str())
+        };
+        writer.write_header(&mut std::io::stdout()).map_err(|e| { error!("Unable to write the VCF header: {:?}", e); })?;
+    }
+
+    let target_regions = if matches.is_present("region") || matches.is_present("regions") {
+        let mut regions = if let Some(path) = matches.value_of("regions") { RegionSet::load(path)? } else { RegionSet::new() };
+        for spec in matches.values_of("region").into_iter().flatten()
+        {
+            let (chrom, range) = RegionSet::parse_region(spec, &contigs[0..])?;
+            regions.insert(&chrom, range.0, range.1);
+        }
+        Some(regions)
+    } else { None };
+
+    let region_padding = matches.value_of("region-padding").unwrap_or("1000000").parse::<u32>().map_err(|_| {
+        error!("Invalid region padding {}", matches.value_of("region-padding").unwrap_or(""));
+    })?;
+
+    /* The list of (chromosome, region) to call, in the genome order */
+    let target_list:Vec<(u32, Option<(u32, u32)>)> = if let Some(ref regions) = target_regions {
+        contigs.iter().enumerate()
+            .flat_map(|(idx, (name, _))| regions.get_chrom_regions(name).iter().map(move |range| {
+                debug!("Selected region chromosome id={} name={} range={}-{}", idx, name, range.0, range.1);
+                (idx as u32, Some(*range))
+            }))
+            .collect()
+    } else {
+        contigs.iter().map(|(name, _)| name).enumerate()
+            .filter(|(_, name)| include_pattern.is_match(&name) && !exclude_pattern.is_match(&name))
+            .map(|(idx, name)| {
+                debug!("Selected chromosome id={} name={}", idx, name);
+                (idx as u32, None)
+            }).collect()
+    };
+
+    /* The targeted calling only scans the padded regions, so the scanner dump isn't used */
+    let scanner_dump = if matches.is_present("no-scanner-dump") || target_regions.is_some() || from_stdin || coverage.is_some() { None } else {
+        let path = format!("{}.limodump", matches.value_of("scanner-dump-path").unwrap_or(alignment));
+        let fingerprint = Fingerprint::of_alignment(alignment, &read_filter, &mapq)?;
+        let container = DumpContainer::open(&path, &fingerprint, matches.is_present("trust-dump")).map_err(|e| {
+            error!("Unable to open the scanner dump {}: {}, remove it or run with --no-scanner-dump", path, e);
+        })?;
+        Some(Arc::new(container))
+    };
+
+    /* The GC-to-depth curve is fitted once for the sample from the ranges sampled over the
+     * selected chromosomes, which requires the random access to the alignment file */
+    let gc_curve = if matches.is_present("gc-correction") {
+        let reference = matches.value_of("reference").unwrap_or("");
+
+        if !has_index
+        {
+            error!("The GC correction samples the depth across the genome, which requires an indexed alignment file");
+            return Err(());
+        }
+
+        let sampled:Vec<_> = contigs.iter()
+            .filter(|(name, _)| include_pattern.is_match(name) && !exclude_pattern.is_match(name))
+            .cloned().collect();
+        let mut corrected:Vec<_> = target_list.iter().map(|(i, _)| contigs[*i as usize].clone()).collect();
+        corrected.dedup();
+
+        info!("Fitting the GC-to-depth curve of the sample");
+
+        let curve = GcCurve::fit(reference, &sampled[0..], &corrected[0..], window_size, |chrom, begin, end| {
+            let idx = contigs.iter().position(|(name, _)| name == chrom).unwrap_or(0);
+            let mut bam = BamFile::new(alignment, idx as u32, Some(reference))?;
+            bam.set_range(begin as usize, end as usize);
+            bam.set_io_threads(nthreads.saturating_sub(1) as u32)?;
+            let scanner = Scanner::new(&bam, &read_filter, &mapq)?;
+            Ok(window_depth(&scanner, window_size))
+        })?;
+
+        Some(Arc::new(curve))
+    } else { None };
+
+    /* Each task scans with its own decompression threads, so the thread budget is split
+     * between the tasks and the decompression threads of each task. By default all the tasks
+     * run in parallel and the spare threads go to decompression */
+    let io_threads = if let Some(val) = matches.value_of("io-threads") {
+        let io_threads = val.parse::<usize>().expect("Invalid number of threads");
+        nthreads = (nthreads / (io_threads + 1)).max(1);
+        io_threads
+    } else {
+        let workers = nthreads.min(target_list.len()).max(1);
+        (nthreads - workers) / workers
+    };
 
     nthreads = nthreads.min(target_list.len());
 
-    info!("Starting {} threads for {} chroms", nthreads, target_list.len());
+    info!("Starting {} threads for {} tasks, {} decompression threads per task", nthreads, target_list.len(), io_threads);
     
     let tp = if nthreads > 1 { Some(ThreadPool::new(nthreads)) } else { None };
 
-    for i in target_list.into_iter()
+    let num_tasks = target_list.len();
+    let task_chroms:Vec<u32> = target_list.iter().map(|(i, _)| *i).collect();
+    let (result_tx, result_rx) = channel();
+
+    /* The tasks don't decompress anything in the single pass mode, all the spare threads
+     * go to the stream */
+    if let Some(ref mut stream) = stream
+    {
+        stream.set_io_threads((io_threads * nthreads) as u32)?;
+    }
+
+    /* The number of scanners waiting for a worker is limited by the slots, since each of them
+     * holds the depth of a whole contig */
+    let (slot_tx, slot_rx) = channel();
+
+    /* The coverage files are read in the contig order, like the single pass mode */
+    let mut coverage_readers = if let Some(path) = coverage {
+        let total = CoverageReader::open(path, &contigs)?;
+        let mapq0 = if let Some(path) = matches.value_of("mapq0-coverage") { Some(CoverageReader::open(path, &contigs)?) } else { None };
+        Some((total, mapq0))
+    } else { None };
+
+    let coverage_read_len = matches.value_of("coverage-read-length").unwrap_or("150").parse::<u32>().expect("Invalid read length");
+
+    for _ in 0..nthreads.max(1)
+    {
+        slot_tx.send(()).expect("Failed");
+    }
+
+    for (task_idx, (i, region)) in target_list.into_iter().enumerate()
     {
+        let scanner = if let Some(ref mut stream) = stream {
+            let (ref name, length) = contigs[i as usize];
+            slot_rx.recv().expect("Failed");
+
+            /* The task can't open the alignment file without the index, so the scanner dump
+             * is loaded here as well */
+            let loaded = scanner_dump.as_ref().and_then(|container| container.load(name, length as u64).unwrap_or_else(|e| {
+                warn!("Unable to load {} from the scanner dump {}: {}, rebuilding it from the alignment file", name, container.get_path(), e);
+                None
+            }));
+
+            if loaded.is_some() { loaded } else {
+                info!("Chrom {}: Scanning the alignment file", name);
+                Some(Scanner::from_stream(&mut stream.contig(i)?, &read_filter, &mapq)?)
+            }
+        } else if let Some((ref mut total, ref mut mapq0)) = coverage_readers {
+            let (ref name, length) = contigs[i as usize];
+            slot_rx.recv().expect("Failed");
+            info!("Chrom {}: Loading the coverage file", name);
+            Some(Scanner::from_coverage(name, length, coverage_read_len, total, mapq0.as_mut())?)
+        } else { None };
+
+        let holds_slot = scanner.is_some();
+
         let task = Task {
             alignment: alignment.to_string(),
-            scanner_dump: matches.value_of("scanner-dump-path").unwrap_or(alignment).to_string(),
-            no_scanner_dump: matches.is_present("no-scanner-dump"),
+            reference: matches.value_of("reference").map(|x| x.to_string()),
+            scanner_dump: scanner_dump.clone(),
+            compress_scanner_dump: !matches.is_present("uncompressed-scanner-dump"),
+            io_threads: io_threads as u32,
+            read_filter: read_filter.clone(),
+            mapq: mapq.clone(),
             chrom: i,
             dump_fe: matches.value_of("dump-frontend-events").map(|x| x.to_string()),
             dump_ep: matches.value_of("dump-event-pairs").map(|x| x.to_string()),
             copy_nums: copy_nums.clone(),
-            window_size: window_size,
-            enable_pv: matches.value_of("prob-validate").map_or(true, |val| val != "off"),
-            pv_threshold: matches.value_of("prob-validate").map_or(0.2, |val| f64::from_str(val).unwrap()),
+            window_size,
+            model,
+            model_param: matches.value_of("model-param").map(|x| x.to_string()),
+            hmm,
+            score_threshold,
+            gc_curve: gc_curve.clone(),
+            enable_pv: has_index && matches.value_of("prob-validate").is_none_or(|val| val != "off"),
+            pv_threshold,
             cluster_merge: !matches.is_present("no-cluster-merge"),
             load_events: matches.value_of("load-events").map(|x| x.to_string()),
+            output_vcf,
+            filter: filter.clone(),
+            exclude_regions: exclude_regions.clone(),
+            region,
+            region_padding,
+            scanner,
         };
 
-        if let Some(ref tp) = tp {
-            tp.execute(move || { task.run().expect("Failed"); });
-        } else {
-            task.run().expect("Failed");
+        let result_tx = result_tx.clone();
+        let slot_tx = slot_tx.clone();
+
+        let chrom_name = contigs[i as usize].0.clone();
+
+        /* A panicking task still reports its failure and returns its slot, otherwise the
+         * dispatcher would wait for the slot forever */
+        let run = move || {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || task.run())).unwrap_or_else(|_| {
+                error!("Chrom {}: The task panicked", chrom_name);
+                Err(())
+            });
+            if holds_slot { slot_tx.send(()).unwrap_or(()); }
+            result_tx.send((task_idx, result)).unwrap_or(());
+        };
+
+        if let Some(ref tp) = tp { tp.execute(run); } else { run(); }
+    }
+
+    drop(result_tx);
+
+    /* Chromosomes may finish in any order, so we hold the results until all the chromosomes
+     * before it in the header order have been written out */
+    let mut pending:Vec<Option<Vec<task::Record>>> = vec![None; num_tasks];
+    let mut next_task = 0;
+    /* The variant spanning the gap between two target regions is called by both of the tasks */
+    let mut written = HashSet::new();
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    for (task_idx, result) in result_rx
+    {
+        pending[task_idx] = Some(result?);
+
+        while next_task < num_tasks && pending[next_task].is_some()
+        {
+            if next_task > 0 && task_chroms[next_task - 1] != task_chroms[next_task]
+            {
+                written.clear();
+            }
+
+            for (key, record) in pending[next_task].take().unwrap()
+            {
+                if !written.insert(key) { continue; }
+                writeln!(out, "{}", record).map_err(|e| { error!("Unable to write the output: {:?}", e); })?;
+            }
+            next_task += 1;
         }
     }
 
+    if next_task < num_tasks
+    {
+        error!("Some of the chromosomes are not processed successfully");
+        return Err(());
+    }
+
     if let Some(ref tp) = tp {
         tp.join();
     }
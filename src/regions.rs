@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+
+use log::error;
+
+/* A set of genome intervals loaded from a BED file, the intervals of each chromosome are
+ * sorted and merged, so the overlap queries are a binary search */
+pub struct RegionSet {
+    regions: HashMap<String, Vec<(u32, u32)>>
+}
+
+fn merge_intervals(intervals: &mut Vec<(u32, u32)>)
+{
+    intervals.sort();
+
+    let mut merged = Vec::<(u32, u32)>::with_capacity(intervals.len());
+
+    for (begin, end) in intervals.iter()
+    {
+        match merged.last_mut() {
+            Some(last) if last.1 >= *begin => { last.1 = last.1.max(*end); }
+            _ => merged.push((*begin, *end))
+        }
+    }
+
+    *intervals = merged;
+}
+
+impl RegionSet {
+    pub fn new() -> Self
+    {
+        return RegionSet { regions: HashMap::new() };
+    }
+
+    pub fn insert(&mut self, chrom: &str, begin: u32, end: u32)
+    {
+        if begin >= end { return; }
+        let intervals = self.regions.entry(chrom.to_string()).or_default();
+        intervals.push((begin, end));
+        merge_intervals(intervals);
+    }
+
+    /* Parse a samtools style region string against the contigs, for example
+     * chr1:1,000,000-5,000,000, the coordinate is 1-based and inclusive. A single position like
+     * chr1:1,000,000 is the 1 bp region, and only the contig name is the whole contig. Like
+     * samtools, the whole string is looked up in the contigs first, so a contig name with a
+     * colon like HLA-A*01:01 is still a contig */
+    pub fn parse_region(spec: &str, contigs: &[(String, usize)]) -> Result<(String, (u32, u32)), ()>
+    {
+        let find = |name: &str| contigs.iter().find(|(contig, _)| contig == name).map(|(_, len)| *len as u32);
+
+        if let Some(len) = find(spec) { return Ok((spec.to_string(), (0, len))); }
+
+        let (chrom, range) = if let Some(colon) = spec.rfind(':') {
+            let suffix = &spec[colon + 1..];
+            let range:Vec<_> = suffix.split('-').map(|s| s.replace(',', "").parse::<u32>()).collect();
+
+            match range[0..] {
+                [Ok(pos)] => (&spec[..colon], (pos, pos)),
+                [Ok(begin), Ok(end)] => (&spec[..colon], (begin, end)),
+                _ => {
+                    if suffix.chars().all(|c| c.is_ascii_digit() || c == ',' || c == '-') {
+                        error!("Invalid region: {}", spec);
+                    } else {
+                        error!("Unknown contig {}", spec);
+                    }
+                    return Err(());
+                }
+            }
+        } else {
+            error!("Unknown contig {}", spec);
+            return Err(());
+        };
+
+        let length = if let Some(length) = find(chrom) { length } else {
+            error!("Unknown contig {} in the region {}", chrom, spec);
+            return Err(());
+        };
+
+        if range.0 < 1 || range.0 > range.1 || range.0 > length
+        {
+            error!("Invalid region: {}", spec);
+            return Err(());
+        }
+
+        return Ok((chrom.to_string(), (range.0 - 1, range.1.min(length))));
+    }
+
+    /* The range with the padding on both sides, the end is clamped to the chromosome later */
+    pub fn pad_range(range: (u32, u32), padding: u32) -> (u32, u32)
+    {
+        return (range.0.saturating_sub(padding), range.1.saturating_add(padding));
+    }
+
+    pub fn load(path: &str) -> Result<Self, ()>
+    {
+        let fp = std::fs::File::open(path).map_err(|e| { error!("Cannot open the BED file {}: {:?}", path, e); })?;
+        return Self::read_bed(BufReader::new(fp), path);
+    }
+
+    /* Read the BED lines, the path is only used by the error messages */
+    fn read_bed<R: BufRead>(reader: R, path: &str) -> Result<Self, ()>
+    {
+        let mut regions = HashMap::<String, Vec<(u32, u32)>>::new();
+
+        for line in reader.lines()
+        {
+            let line = line.map_err(|e| { error!("Cannot read the BED file {}: {:?}", path, e); })?;
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") { continue; }
+
+            let fields:Vec<_> = line.split('\t').collect();
+
+            if fields.len() < 3
+            {
+                error!("Malformed BED line in {}: {}", path, line);
+                return Err(());
+            }
+
+            let parse = |s:&str| s.trim().parse::<u32>().map_err(|_| { error!("Malformed BED line in {}: {}", path, line); });
+            let (begin, end) = (parse(fields[1])?, parse(fields[2])?);
+
+            if begin < end
+            {
+                regions.entry(fields[0].to_string()).or_default().push((begin, end));
+            }
+        }
+
+        regions.values_mut().for_each(merge_intervals);
+
+        return Ok(RegionSet { regions });
+    }
+
+    pub fn get_chrom_regions(&self, chrom: &str) -> &[(u32, u32)]
+    {
+        return self.regions.get(chrom).map_or(&[], |v| &v[0..]);
+    }
+
+    /* The number of bases in [begin, end) covered by the region set */
+    pub fn overlap(&self, chrom: &str, begin: u32, end: u32) -> u32
+    {
+        let intervals = self.get_chrom_regions(chrom);
+
+        let first = match intervals.binary_search_by(|(_, e)| e.cmp(&begin)) {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx
+        };
+
+        return intervals[first..].iter()
+            .take_while(|(b, _)| *b < end)
+            .fold(0, |s, (b, e)| s + end.min(*e) - begin.max(*b));
+    }
+
+    pub fn overlap_fraction(&self, chrom: &str, begin: u32, end: u32) -> f64
+    {
+        if begin >= end { return 0.0; }
+        return self.overlap(chrom, begin, end) as f64 / (end - begin) as f64;
+    }
+}
+
+#[cfg(test)]
+mod regions_test {
+    use super::*;
+
+    #[test]
+    fn test_overlap()
+    {
+        let mut regions = RegionSet::new();
+        regions.insert("chr1", 100, 200);
+        regions.insert("chr1", 150, 250);
+        regions.insert("chr1", 300, 400);
+        regions.insert("chr1", 500, 500);
+
+        assert_eq!(regions.get_chrom_regions("chr1"), &[(100, 250), (300, 400)]);
+        assert!(regions.get_chrom_regions("chr2").is_empty());
+
+        /* The intervals are half-open, so the adjacent ones don't overlap */
+        assert_eq!(regions.overlap("chr1", 0, 100), 0);
+        assert_eq!(regions.overlap("chr1", 250, 300), 0);
+        assert_eq!(regions.overlap("chr1", 99, 101), 1);
+        assert_eq!(regions.overlap("chr1", 249, 301), 2);
+        assert_eq!(regions.overlap("chr1", 0, 1000), 250);
+        assert_eq!(regions.overlap("chr2", 0, 1000), 0);
+
+        assert_eq!(regions.overlap_fraction("chr1", 200, 300), 0.5);
+        assert_eq!(regions.overlap_fraction("chr1", 200, 200), 0.0);
+    }
+
+    #[test]
+    fn test_load()
+    {
+        let read = |data: &[u8]| RegionSet::read_bed(std::io::Cursor::new(data), "test.bed");
+
+        let regions = read(b"track name=test\n#comment\nchr1\t300\t400\nchr1\t100\t200\textra\nchr2\t10\t10\n").unwrap();
+        assert_eq!(regions.get_chrom_regions("chr1"), &[(100, 200), (300, 400)]);
+        assert!(regions.get_chrom_regions("chr2").is_empty());
+
+        assert!(read(b"chr1\t100\n").is_err());
+        assert!(read(b"chr1\tabc\t200\n").is_err());
+
+        assert!(RegionSet::load("/nonexistent/regions.bed").is_err());
+    }
+
+    #[test]
+    fn test_parse_region()
+    {
+        let contigs = vec![("chr1".to_string(), 10000), ("chrUn".to_string(), 100), ("HLA-A*01:01".to_string(), 3000)];
+        let parse = |spec| RegionSet::parse_region(spec, &contigs[0..]);
+
+        /* 1-based inclusive to 0-based half-open */
+        assert_eq!(parse("chr1:1,000-2,000"), Ok(("chr1".to_string(), (999, 2000))));
+        assert_eq!(parse("chr1:1-1"), Ok(("chr1".to_string(), (0, 1))));
+        assert_eq!(parse("chr1"), Ok(("chr1".to_string(), (0, 10000))));
+        assert_eq!(parse("HLA-A*01:01"), Ok(("HLA-A*01:01".to_string(), (0, 3000))));
+        assert_eq!(parse("HLA-A*01:01:5-10"), Ok(("HLA-A*01:01".to_string(), (4, 10))));
+        assert_eq!(parse("chrUn:1-1000"), Ok(("chrUn".to_string(), (0, 100))));
+
+        /* The single position */
+        assert_eq!(parse("chr1:5,000"), Ok(("chr1".to_string(), (4999, 5000))));
+
+        assert!(parse("chrZ").is_err());
+        assert!(parse("chrZ:1-100").is_err());
+        assert!(parse("chr1:abc").is_err());
+        assert!(parse("chr1:0-100").is_err());
+        assert!(parse("chr1:200-100").is_err());
+        assert!(parse("chr1:20000-30000").is_err());
+        assert!(parse("chr1:100-").is_err());
+        assert!(parse("chr1:-100").is_err());
+        assert!(parse("chr1:1-2-3").is_err());
+        assert!(parse("chr1:1-99999999999").is_err());
+    }
+
+    #[test]
+    fn test_pad_range()
+    {
+        assert_eq!(RegionSet::pad_range((5000, 6000), 1000), (4000, 7000));
+        assert_eq!(RegionSet::pad_range((500, 6000), 1000), (0, 7000));
+        assert_eq!(RegionSet::pad_range((500, u32::MAX - 10), 1000), (0, u32::MAX));
+    }
+}
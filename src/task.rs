@@ -1,56 +1,122 @@
 use std::cmp::{max,min};
 use frontend::prelude::*;
+use frontend::container::DumpContainer;
+use frontend::scanner::{Scanner, MapqConfig};
+use frontend::read_filter::ReadFilter;
+use frontend::frontend::ScoreThreshold;
+use frontend::models::{ModelKind, ModelVisitor};
+use frontend::hmm::HmmParam;
+use frontend::depth_model::DepthModel;
+use frontend::reference::Reference;
+use frontend::gc::GcCurve;
 use crate::edge::{EdgeDetector, Variant};
+use crate::vcf::VcfWriter;
+use crate::filter::VariantFilter;
+use crate::regions::RegionSet;
 
-use log::{info, debug};
+use std::sync::Arc;
+
+use log::{info, warn, debug};
+
+/* The event pairs overlapping the excluded regions more than this fraction are skipped */
+const MAX_EXCLUDED_FRACTION: f64 = 0.5;
 
 pub struct Task {
     pub alignment: String,
-    pub scanner_dump: String,
-    pub no_scanner_dump: bool,
+    /* The reference FASTA, which is required by CRAM input */
+    pub reference: Option<String>,
+    pub scanner_dump: Option<Arc<DumpContainer>>,
+    pub compress_scanner_dump: bool,
+    pub io_threads: u32,
+    pub read_filter: ReadFilter,
+    pub mapq: MapqConfig,
     pub chrom: u32,
     pub dump_fe: Option<String>,
     pub dump_ep: Option<String>,
     pub copy_nums: Vec<u32>,
     pub window_size: u32,
+    pub model: ModelKind,
+    pub model_param: Option<String>,
+    /* Segment the depth with the HMM instead of pairing the model events */
+    pub hmm: Option<HmmParam>,
+    pub score_threshold: ScoreThreshold<f64>,
+    /* The GC-to-depth curve of the sample, fitted before the tasks are started */
+    pub gc_curve: Option<Arc<GcCurve>>,
     pub enable_pv: bool,
     pub pv_threshold: f64,
     pub cluster_merge: bool,
     pub load_events: Option<String>,
+    pub output_vcf: bool,
+    pub filter: VariantFilter,
+    pub exclude_regions: Option<Arc<RegionSet>>,
+    /* The target region in genome coordinate, None for the whole chromosome */
+    pub region: Option<(u32, u32)>,
+    pub region_padding: u32,
+    /* The scanner built by the single pass over the alignment file */
+    pub scanner: Option<Scanner>,
 }
 
+/* The output record of a variant, keyed by its position and copy number, so the variant called
+ * by the tasks of the adjacent target regions is written once */
+pub type Record = ((u32, u32, u32), String);
+
 impl Task {
 
-    pub fn run(&self) -> Result<(), ()>
+    /* Run the detection for the chromosome and return the formatted output records,
+     * the caller is responsible for writing them in the genome order */
+    pub fn run(self) -> Result<Vec<Record>, ()>
+    {
+        let model = self.model;
+        return model.dispatch(self);
+    }
+
+    fn run_model<DM:DepthModel<Output = f64> + std::fmt::Debug>(mut self) -> Result<Vec<Record>, ()>
     {
+        let scanner = self.scanner.take();
+
         let frontend_param = FrontendParam {
             alignment: self.alignment.as_str(),
-            scanner_dump: self.scanner_dump.as_str(),
-            no_scanner_dump: self.no_scanner_dump,
+            reference: self.reference.as_deref(),
+            scanner_dump: self.scanner_dump.as_ref().map(|c| c.as_ref()),
+            compress_scanner_dump: self.compress_scanner_dump,
+            io_threads: self.io_threads,
+            read_filter: self.read_filter.clone(),
+            mapq: self.mapq.clone(),
             chrom: self.chrom,
             dump_fe: self.dump_fe.iter().fold(None, |_,x| Some(x.as_str())),
             dump_ep: self.dump_ep.iter().fold(None, |_,x| Some(x.as_str())),
             copy_nums: self.copy_nums.clone(),
-            window_size: self.window_size
+            window_size: self.window_size,
+            model_param: self.model_param.as_deref(),
+            score_threshold: self.score_threshold,
+            gc_curve: self.gc_curve.as_ref().map(|c| c.as_ref()),
+            range: self.region.map(|range| RegionSet::pad_range(range, self.region_padding)),
         };
 
         let prob_args = if self.enable_pv {
-            Some((frontend_param.alignment, None, frontend_param.chrom, self.pv_threshold))
+            Some((frontend_param.alignment, frontend_param.reference, frontend_param.chrom, self.pv_threshold))
         } else { None };
 
 
-        let frontend_ctx = run_linear_frontend(frontend_param.clone())?;
+        let frontend_ctx = if let Some(scanner) = scanner {
+            run_frontend_with_scanner::<DM>(frontend_param.clone(), scanner)?
+        } else {
+            run_frontend::<DM>(frontend_param.clone())?
+        };
         
         let  chrom_name = frontend_ctx.get_chrom_name();
 
+        /* All the positions we got from the frontend and edge detector are relative to the scanned range */
+        let offset = frontend_ctx.frontend.get_scanner().get_offset();
+
         debug!("Chrom {}: Constructing event detection context", chrom_name);
 
-        let mut edge_detect = EdgeDetector::new(&frontend_ctx.frontend, frontend_ctx.frontend.get_scan_size() * 2, &frontend_param.copy_nums[0..], prob_args);
+        let mut edge_detect = EdgeDetector::new(&frontend_ctx.frontend, frontend_ctx.frontend.get_scan_size() * 2, &frontend_param.copy_nums[0..], prob_args, &self.read_filter);
         
         let mut events:Vec<_> = if self.load_events.is_none() {
 
             info!("Chrom {}: Collecting event pair", chrom_name);
-            let event_pair = frontend_ctx.get_result();
+            let event_pair = if let Some(ref param) = self.hmm { frontend_ctx.get_hmm_result(param) } else { frontend_ctx.get_result() };
             
             info!("Chrom {}: Post processing the paired events", chrom_name);
             let report_unit = 10000000;
@@ -58,21 +124,40 @@ impl Task {
             let mut last_mb = report_unit;
             let mut event_count = 0;
             let mut passed = 0;
+            let mut excluded = 0;
 
-            event_pair.iter().filter_map(|ep| {
+            let result = event_pair.iter().filter_map(|ep| {
                 if ep.0.pos > last_mb {
                     debug!("Chrom {}: Postprocess - Offset:{}MB/{}MB, FE_Events:{}, Passed:{}", ep.0.chrom, last_mb/1000000, total_mb, event_count, passed);
-                    last_mb = ((ep.0.pos + report_unit - 1) / report_unit) * report_unit;
+                    last_mb = ep.0.pos.div_ceil(report_unit) * report_unit;
                 }
                 event_count += 1;
 
+                if let Some(ref regions) = self.exclude_regions {
+                    if regions.overlap_fraction(chrom_name, ep.0.pos + offset, ep.1.pos + offset) > MAX_EXCLUDED_FRACTION {
+                        excluded += 1;
+                        return None;
+                    }
+                }
+
                 edge_detect.detect_edge(ep, true).map(|x| { passed += 1; x })
-            }).collect()
+            }).collect();
+
+            if excluded > 0 {
+                info!("Chrom {}: {} event pairs skipped due to the excluded regions", chrom_name, excluded);
+            }
+
+            result
         } else {
+            let scan_size = frontend_ctx.frontend.get_chrom_size();
             edge_detect.load_variants(std::fs::File::open(self.load_events.as_ref().unwrap()).expect("Cannot open event file"))
+                .into_iter()
+                .filter(|sv| sv.left_pos >= offset && sv.right_pos < offset + scan_size)
+                .map(|mut sv| { sv.left_pos -= offset; sv.right_pos -= offset; sv })
+                .collect()
         };
 
-        let events = if self.cluster_merge {
+        let mut events = if self.cluster_merge {
             info!("Chrom {}: Merging the clustered events", chrom_name);
             events.sort_by(|a,b| a.left_pos.cmp(&b.left_pos));
             let mut cluster_range = (0,0);
@@ -112,31 +197,37 @@ impl Task {
                         {
                             let pv_diff_thres_a = if merged { 0.15 } else { 0.005 };
                             let pv_diff_thres_b = if merged { 0.005 } else { -1.0 };
+                            /* The distance of the mean from the estimated level, or the level of the copy number */
+                            let offset = |v:&Variant| (v.mean - v.level.unwrap_or(0.5 * v.copy_num as f64)).abs();
                             if (best.pv_score - sv.pv_score).abs() > pv_diff_thres_a { 
                                 if best.pv_score < sv.pv_score { return sv; }
                             } else if best.boundary != sv.boundary {
                                 if !best.boundary { return sv; }
                             } else if (best.pv_score - sv.pv_score).abs() > pv_diff_thres_b { 
                                 if best.pv_score < sv.pv_score { return sv; }
-                            }  else if ((best.mean - 0.5 * best.copy_num as f64).abs() - (sv.mean - 0.5 * sv.copy_num as f64).abs()).abs() > 0.005 { 
-                                if (best.mean - 0.5 * best.copy_num as f64).abs() > (sv.mean - 0.5 * sv.copy_num as f64).abs() { return sv; }
+                            }  else if (offset(best) - offset(sv)).abs() > 0.005 { 
+                                if offset(best) > offset(sv) { return sv; }
                             } else if (best.sd - sv.sd).abs() > 0.005 {
                                 if best.sd > sv.sd { return sv; }
                             }
                             return best;
-                        };
+                        }
 
                         /* Option 1: Select a best SV from the cluster */
                         let best = cluster.iter().skip(1).fold(cluster[0], |a,b| update(a, *b, false));
 
                         /* Option 2: Merge all the SV in the cluster */
-                        let event_pair = make_linear_event(cluster[0].chrom, cluster[0].left_pos, cluster[cluster.len()-1].right_pos, best.copy_num);
+                        let mut event_pair = make_event(cluster[0].chrom, cluster[0].left_pos, cluster[cluster.len()-1].right_pos, best.copy_num);
+                        event_pair.0.level = best.level;
+                        event_pair.1.level = best.level;
                         let cluster_event = edge_detect.detect_edge(&event_pair, true);
                         let mut best = cluster_event.iter().fold(best, |a, x| update(a, &x, true)).clone();
                         
                         /* Option 3: Also, it's possible we are in the middle of a huge event */
                         if cluster[cluster.len()-1].right_pos - cluster[0].left_pos > 5000 {
-                            let mut event_pair = make_linear_event(cluster[0].chrom, cluster[0].left_pos, cluster[cluster.len()-1].right_pos, best.copy_num);
+                            let mut event_pair = make_event(cluster[0].chrom, cluster[0].left_pos, cluster[cluster.len()-1].right_pos, best.copy_num);
+                            event_pair.0.level = best.level;
+                            event_pair.1.level = best.level;
                             if let Some(ret) = edge_detect.extend_region(&mut event_pair, 2000) {
                                 best = update(&best, &ret, true).clone();
                             }
@@ -165,13 +256,62 @@ impl Task {
             events
         };
 
-        for sv in events 
+        edge_detect.annotate_mapq_tiers(&mut events[0..]);
+
+        for sv in events.iter_mut()
         {
-            println!("{}\t{}\t{}\t{}", sv.chrom, sv.left_pos, sv.right_pos, sv.json_repr());
+            sv.left_pos += offset;
+            sv.right_pos += offset;
         }
 
+        if let Some((begin, end)) = self.region
+        {
+            events.retain(|sv| sv.left_pos < end && sv.right_pos > begin);
+        }
+
+        /* The same variant may be called from more than one event pair */
+        events.sort_by_key(|sv| (sv.left_pos, sv.right_pos, sv.copy_num));
+        events.dedup_by_key(|sv| (sv.left_pos, sv.right_pos, sv.copy_num));
+
+        for sv in events.iter_mut()
+        {
+            self.filter.apply(sv);
+
+            if let Some(ref regions) = self.exclude_regions {
+                let frac = regions.overlap_fraction(sv.chrom, sv.left_pos, sv.right_pos);
+                if frac > 0.0 { sv.excluded_frac = Some(frac); }
+            }
+        }
+
+        /* The REF of the VCF records is the anchor base from the reference */
+        let reference = if self.output_vcf { self.reference.as_ref().and_then(|path| Reference::open(path).ok()) } else { None };
+
+        if self.output_vcf && self.reference.is_some() && reference.is_none()
+        {
+            warn!("Chrom {}: The reference isn't indexed, the REF of the VCF records is N", chrom_name);
+        }
+
+        let records = events.iter().filter_map(|sv| {
+            let line = if self.output_vcf {
+                let ref_base = reference.as_ref().and_then(|fa| fa.fetch_base(sv.chrom, sv.left_pos.max(1) - 1).ok());
+                VcfWriter::format_record(sv, ref_base)
+            } else {
+                Some(format!("{}\t{}\t{}\t{}", sv.chrom, sv.left_pos, sv.right_pos, sv.json_repr()))
+            };
+            line.map(|line| ((sv.left_pos, sv.right_pos, sv.copy_num), line))
+        }).collect();
+
         info!("Chrom {}: Done", chrom_name);
 
-        return Ok(());
+        return Ok(records);
+    }
+}
+
+impl ModelVisitor for Task {
+    type Output = Result<Vec<Record>, ()>;
+
+    fn visit<DM:DepthModel<Output = f64> + std::fmt::Debug>(self) -> Self::Output
+    {
+        return self.run_model::<DM>();
     }
 }
@@ -0,0 +1,206 @@
+use std::io::Write;
+use crate::edge::Variant;
+use frontend::header::BamHeader;
+
+use log::warn;
+
+/* The INFO fields we carry over from the variant record, (id, number, type, description) */
+const INFO_FIELDS : [(&str, &str, &str, &str); 11] = [
+    ("SVTYPE",   "1", "String",  "Type of structural variant"),
+    ("END",      "1", "Integer", "End position of the variant described in this record"),
+    ("SVLEN",    "1", "Integer", "Difference in length between REF and ALT alleles"),
+    ("PV_SCORE", "1", "Float",   "Score of the read-depth/fragment-depth probability validation"),
+    ("MEAN",     "1", "Float",   "Mean of the normalized read depth inside the event"),
+    ("SD",       "1", "Float",   "Standard deviation of the normalized read depth inside the event"),
+    ("LMQ_MEAN", "1", "Float",   "Mean of the normalized low mapping quality read depth inside the event"),
+    ("BOUNDARY", "0", "Flag",    "Both boundaries of the event are supported by a depth change"),
+    ("EXCLUDED_FRAC", "1", "Float", "Fraction of the event overlapping the excluded regions"),
+    ("MAPQ_TIERS", ".", "String", "Mean of the normalized depth of the reads below each MAPQ cutoff, as cutoff:mean"),
+    ("LEVEL",    "1", "Float",   "Normalized read depth inside the event estimated by the depth model, which gives the copy number")
+];
+
+pub struct VcfWriter {
+    contigs: Vec<(String, usize)>,
+    filters: Vec<(&'static str, String)>,
+    sample : String,
+}
+
+impl VcfWriter {
+    /* The sample column is named after the SM tag of the read groups, default_sample is used
+     * if the header doesn't have one */
+    pub fn new(header: &BamHeader, filters: Vec<(&'static str, String)>, default_sample: &str) -> Self
+    {
+        let samples = header.samples();
+
+        if samples.len() > 1
+        {
+            warn!("The alignment file contains multiple samples {:?}, using {} as the sample name", samples, samples[0]);
+        }
+
+        let sample = samples.into_iter().next().unwrap_or_else(|| default_sample.to_string());
+
+        return VcfWriter::with_contigs(header.contigs(), filters, &sample);
+    }
+
+    /* Without the alignment file, the contigs come from the reference index */
+    pub fn with_contigs(contigs: Vec<(String, usize)>, filters: Vec<(&'static str, String)>, sample: &str) -> Self
+    {
+        return VcfWriter {
+            contigs,
+            filters,
+            sample: sample.to_string()
+        };
+    }
+
+    pub fn write_header<W:Write>(&self, out: &mut W) -> Result<(), std::io::Error>
+    {
+        writeln!(out, "##fileformat=VCFv4.2")?;
+        writeln!(out, "##source=limo-{}", env!("CARGO_PKG_VERSION"))?;
+        writeln!(out, "##FILTER=<ID=PASS,Description=\"All filters passed\">")?;
+
+        for (id, desc) in self.filters.iter()
+        {
+            writeln!(out, "##FILTER=<ID={},Description=\"{}\">", id, desc)?;
+        }
+
+        for (name, length) in self.contigs.iter()
+        {
+            writeln!(out, "##contig=<ID={},length={}>", name, length)?;
+        }
+
+        writeln!(out, "##ALT=<ID=DEL,Description=\"Deletion\">")?;
+        writeln!(out, "##ALT=<ID=DUP,Description=\"Duplication\">")?;
+
+        for (id, number, ty, desc) in INFO_FIELDS.iter()
+        {
+            writeln!(out, "##INFO=<ID={},Number={},Type={},Description=\"{}\">", id, number, ty, desc)?;
+        }
+
+        writeln!(out, "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">")?;
+        writeln!(out, "##FORMAT=<ID=CN,Number=1,Type=Integer,Description=\"Copy number\">")?;
+        writeln!(out, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\t{}", self.sample)?;
+
+        return Ok(());
+    }
+
+    /* The depth alone doesn't tell how the extra copies are distributed between the haplotypes,
+     * so a single extra copy is taken as heterozygous and more copies as homozygous, the same
+     * as the losses. The CN field carries the actual copy number */
+    fn genotype(copy_num: u32) -> &'static str
+    {
+        match copy_num {
+            0 => "1/1",
+            1 => "0/1",
+            2 => "0/0",
+            3 => "0/1",
+            _ => "1/1"
+        }
+    }
+
+    /* The record of the variant, None for the copy neutral ones, which aren't variants. The REF
+     * is the anchor base from the reference if it's available, otherwise N */
+    pub fn format_record(sv: &Variant, ref_base: Option<u8>) -> Option<String>
+    {
+        let (sv_type, sv_len) = match sv.copy_num {
+            2 => return None,
+            cn if cn < 2 => ("DEL", -((sv.right_pos - sv.left_pos) as i64)),
+            _ => ("DUP", (sv.right_pos - sv.left_pos) as i64)
+        };
+
+        /* The symbolic allele is anchored at the base right before the event, which is the
+         * 1-based coordinate of the 0-based left position */
+        let pos = sv.left_pos.max(1);
+
+        /* The variant carries the variance of the depth */
+        let mut info = format!("SVTYPE={};END={};SVLEN={};PV_SCORE={:.4};MEAN={:.4};SD={:.4};LMQ_MEAN={:.4}",
+                               sv_type, sv.right_pos, sv_len, sv.pv_score, sv.mean, sv.sd.max(0.0).sqrt(), sv.lmq_mean);
+
+        if sv.boundary { info.push_str(";BOUNDARY"); }
+
+        if let Some(frac) = sv.excluded_frac { info.push_str(&format!(";EXCLUDED_FRAC={:.4}", frac)); }
+
+        if let Some(level) = sv.level { info.push_str(&format!(";LEVEL={:.4}", level)); }
+
+        if !sv.mapq_tiers.is_empty() {
+            let tiers:Vec<_> = sv.mapq_tiers.iter().map(|(cutoff, mean)| format!("{}:{:.4}", cutoff, mean)).collect();
+            info.push_str(&format!(";MAPQ_TIERS={}", tiers.join(",")));
+        }
+
+        let filter = if sv.filters.is_empty() { "PASS".to_string() } else { sv.filters.join(";") };
+
+        return Some(format!("{}\t{}\t.\t{}\t<{}>\t.\t{}\t{}\tGT:CN\t{}:{}",
+                            sv.chrom, pos, ref_base.unwrap_or(b'N') as char, sv_type, filter, info, Self::genotype(sv.copy_num), sv.copy_num));
+    }
+}
+
+#[cfg(test)]
+mod vcf_test {
+    use super::*;
+
+    fn variant(left_pos: u32, right_pos: u32, copy_num: u32) -> Variant<'static>
+    {
+        return Variant {
+            chrom: "chr1",
+            left_pos,
+            right_pos,
+            copy_num,
+            mean: 0.5 * copy_num as f64,
+            sd: 0.04,
+            pv_score: 0.9,
+            lmq_mean: 0.0,
+            boundary: true,
+            mapq_tiers: Vec::new(),
+            filters: Vec::new(),
+            excluded_frac: None,
+            level: None
+        };
+    }
+
+    #[test]
+    fn test_format_del()
+    {
+        let record = VcfWriter::format_record(&variant(1000, 3000, 1), Some(b'A')).unwrap();
+        assert_eq!(record, "chr1\t1000\t.\tA\t<DEL>\t.\tPASS\tSVTYPE=DEL;END=3000;SVLEN=-2000;PV_SCORE=0.9000;MEAN=0.5000;SD=0.2000;LMQ_MEAN=0.0000;BOUNDARY\tGT:CN\t0/1:1");
+    }
+
+    #[test]
+    fn test_format_dup()
+    {
+        let mut sv = variant(0, 500, 4);
+        sv.boundary = false;
+        sv.filters = vec!["LowPvDup".to_string()];
+        sv.excluded_frac = Some(0.25);
+
+        let record = VcfWriter::format_record(&sv, None).unwrap();
+        assert_eq!(record, "chr1\t1\t.\tN\t<DUP>\t.\tLowPvDup\tSVTYPE=DUP;END=500;SVLEN=500;PV_SCORE=0.9000;MEAN=2.0000;SD=0.2000;LMQ_MEAN=0.0000;EXCLUDED_FRAC=0.2500\tGT:CN\t1/1:4");
+
+        assert_eq!(VcfWriter::format_record(&variant(0, 500, 2), None), None);
+    }
+
+    #[test]
+    fn test_format_level()
+    {
+        let mut sv = variant(1000, 3000, 5);
+        sv.level = Some(2.4);
+
+        let record = VcfWriter::format_record(&sv, Some(b'C')).unwrap();
+        assert_eq!(record, "chr1\t1000\t.\tC\t<DUP>\t.\tPASS\tSVTYPE=DUP;END=3000;SVLEN=2000;PV_SCORE=0.9000;MEAN=2.5000;SD=0.2000;LMQ_MEAN=0.0000;BOUNDARY;LEVEL=2.4000\tGT:CN\t1/1:5");
+        assert!(sv.json_repr().contains("\"level\":2.4"));
+        assert!(!variant(1000, 3000, 5).json_repr().contains("level"));
+    }
+
+    #[test]
+    fn test_header()
+    {
+        let writer = VcfWriter::with_contigs(vec![("chr1".to_string(), 1000)], vec![("LowPv", "Low probability".to_string())], "sample");
+        let mut out = Vec::new();
+        writer.write_header(&mut out).unwrap();
+        let header = String::from_utf8(out).unwrap();
+
+        assert!(header.starts_with("##fileformat=VCFv4.2\n"));
+        assert!(header.contains("##FILTER=<ID=LowPv,Description=\"Low probability\">\n"));
+        assert!(header.contains("##contig=<ID=chr1,length=1000>\n"));
+        assert!(header.contains("##INFO=<ID=SD,Number=1,Type=Float,Description=\"Standard deviation of the normalized read depth inside the event\">\n"));
+        assert!(header.ends_with("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tsample\n"));
+    }
+}